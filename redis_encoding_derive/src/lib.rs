@@ -82,14 +82,15 @@ fn impl_from_redis_value(ast: &mut syn::DeriveInput) -> TokenStream {
             fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
                 match v {
                     redis::Value::Data(bs)=>{
-                        let res = serde_json::from_slice::<Self>(bs).unwrap();
-                        return Ok(res);
+                        serde_json::from_slice::<Self>(bs).map_err(|e| {
+                            (redis::ErrorKind::TypeError, "redis value is not valid json", e.to_string()).into()
+                        })
                     }
                     redis::Value::Nil=>{
                         return Err((redis::ErrorKind::ResponseError,"can not find key").into())
                     }
                     _=>{
-                        panic!("redis value is not vec<u8>")
+                        Err((redis::ErrorKind::TypeError, "redis value is not vec<u8>").into())
                     }
                 }
             }
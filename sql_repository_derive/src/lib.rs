@@ -0,0 +1,384 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+
+// #[repository(table = "users", pk = "id", soft_delete = "deleted_at", db = "postgres")]
+//
+// Generates `FromRow`, `insert`, `get_by_id`, `update`, `delete`, `list` and, with
+// `soft_delete`, a `soft_delete` method. Fields accept
+// `#[column(name = "user_name", skip_insert, skip_update)]`.
+// The generated `list` takes the `Pagination` re-exported by the crate. With
+// postgres `insert` returns the primary key through `RETURNING`, elsewhere the
+// query result with its last insert id. Every query runs in a `db.query` span
+// through the crate's `trace::query`.
+#[proc_macro_attribute]
+pub fn repository(param: TokenStream, input: TokenStream) -> TokenStream {
+    let mut args = Args::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    syn::parse_macro_input!(param with parser);
+
+    let mut ast = syn::parse_macro_input!(input as syn::ItemStruct);
+
+    match impl_repository(&args, &mut ast) {
+        Ok(v) => v.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    table: Option<String>,
+    pk: Option<String>,
+    soft_delete: Option<String>,
+    db: Option<String>,
+}
+
+impl Args {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        let value = meta.value()?.parse::<syn::LitStr>()?.value();
+        if meta.path.is_ident("table") {
+            self.table = Some(value);
+        } else if meta.path.is_ident("pk") {
+            self.pk = Some(value);
+        } else if meta.path.is_ident("soft_delete") {
+            self.soft_delete = Some(value);
+        } else if meta.path.is_ident("db") {
+            self.db = Some(value);
+        } else {
+            return Err(meta.error("expected table, pk, soft_delete or db"));
+        }
+        Ok(())
+    }
+}
+
+struct Column {
+    ident: syn::Ident,
+    ty: syn::Type,
+    name: String,
+    skip_insert: bool,
+    skip_update: bool,
+}
+
+fn columns(ast: &mut syn::ItemStruct) -> syn::Result<Vec<Column>> {
+    let fields = match &mut ast.fields {
+        syn::Fields::Named(v) => &mut v.named,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &ast.ident,
+                "repository only supports structs with named fields",
+            ))
+        }
+    };
+
+    let mut res = Vec::new();
+    for field in fields.iter_mut() {
+        let ident = field.ident.clone().unwrap();
+        let mut column = Column {
+            name: ident.to_string(),
+            ident,
+            ty: field.ty.clone(),
+            skip_insert: false,
+            skip_update: false,
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("column")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    column.name = meta.value()?.parse::<syn::LitStr>()?.value();
+                } else if meta.path.is_ident("skip_insert") {
+                    column.skip_insert = true;
+                } else if meta.path.is_ident("skip_update") {
+                    column.skip_update = true;
+                } else {
+                    return Err(meta.error("expected name, skip_insert or skip_update"));
+                }
+                Ok(())
+            })?;
+        }
+        // helper attributes are ours, rustc would reject them
+        field.attrs.retain(|a| !a.path().is_ident("column"));
+        res.push(column);
+    }
+    Ok(res)
+}
+
+struct Dialect {
+    krate: syn::Ident,
+    db: TokenStream2,
+    row: TokenStream2,
+    numbered: bool,
+}
+
+impl Dialect {
+    fn new(db: &str) -> syn::Result<Self> {
+        let (krate, db, row, numbered) = match db {
+            "postgres" => (
+                "util_postgres",
+                quote!(Postgres),
+                quote!(postgres::PgRow),
+                true,
+            ),
+            "mysql" => ("util_mysql", quote!(MySql), quote!(mysql::MySqlRow), false),
+            "sqlite" => (
+                "util_sqlite",
+                quote!(Sqlite),
+                quote!(sqlite::SqliteRow),
+                false,
+            ),
+            other => {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    format!(
+                        "unsupported db {}, expected postgres, mysql or sqlite",
+                        other
+                    ),
+                ))
+            }
+        };
+        Ok(Self {
+            krate: syn::Ident::new(krate, Span::call_site()),
+            db,
+            row,
+            numbered,
+        })
+    }
+
+    fn placeholder(&self, n: usize) -> String {
+        match self.numbered {
+            true => format!("${}", n),
+            false => "?".to_string(),
+        }
+    }
+}
+
+fn impl_repository(args: &Args, ast: &mut syn::ItemStruct) -> syn::Result<TokenStream2> {
+    let table = args
+        .table
+        .clone()
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "missing table"))?;
+    let dialect = Dialect::new(args.db.as_deref().unwrap_or("postgres"))?;
+    let cols = columns(ast)?;
+
+    let pk_name = args.pk.clone().unwrap_or_else(|| "id".to_string());
+    let pk = cols
+        .iter()
+        .find(|c| c.name == pk_name || c.ident == pk_name)
+        .ok_or_else(|| {
+            syn::Error::new_spanned(&ast.ident, format!("primary key {} not found", pk_name))
+        })?;
+    let pk_ident = &pk.ident;
+    let pk_ty = &pk.ty;
+    let pk_column = &pk.name;
+
+    let alive = match &args.soft_delete {
+        Some(v) => format!(" WHERE {} IS NULL", v),
+        None => String::new(),
+    };
+    let alive_and = match &args.soft_delete {
+        Some(v) => format!(" AND {} IS NULL", v),
+        None => String::new(),
+    };
+    let select = cols
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let insert_cols = cols.iter().filter(|c| !c.skip_insert).collect::<Vec<_>>();
+    let insert_sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        insert_cols
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        (1..=insert_cols.len())
+            .map(|n| dialect.placeholder(n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let insert_binds = insert_cols.iter().map(|c| &c.ident);
+
+    let update_cols = cols
+        .iter()
+        .filter(|c| !c.skip_update && c.name != *pk_column)
+        .collect::<Vec<_>>();
+    let update_sql = format!(
+        "UPDATE {} SET {} WHERE {} = {}{}",
+        table,
+        update_cols
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{} = {}", c.name, dialect.placeholder(i + 1)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        pk_column,
+        dialect.placeholder(update_cols.len() + 1),
+        alive_and
+    );
+    let update_binds = update_cols.iter().map(|c| &c.ident);
+
+    let get_sql = format!(
+        "SELECT {} FROM {} WHERE {} = {}{}",
+        select,
+        table,
+        pk_column,
+        dialect.placeholder(1),
+        alive_and
+    );
+    let delete_sql = format!(
+        "DELETE FROM {} WHERE {} = {}",
+        table,
+        pk_column,
+        dialect.placeholder(1)
+    );
+    let count_sql = format!("SELECT COUNT(*) FROM {}{}", table, alive);
+    let list_sql = format!(
+        "SELECT {} FROM {}{} ORDER BY {} LIMIT {} OFFSET {}",
+        select,
+        table,
+        alive,
+        pk_column,
+        dialect.placeholder(1),
+        dialect.placeholder(2)
+    );
+
+    let name = &ast.ident;
+    let krate = &dialect.krate;
+    let db = &dialect.db;
+    let row = &dialect.row;
+    let sqlx = quote!(#krate::sqlx);
+    let result = quote!(#krate::SqlResult);
+    // every generated query gets a `db.query` span
+    let trace = quote!(#krate::trace::query);
+    let getters = cols.iter().map(|c| {
+        let ident = &c.ident;
+        let column = &c.name;
+        quote!(#ident: #sqlx::Row::try_get(row, #column)?)
+    });
+
+    let soft_delete = args.soft_delete.as_ref().map(|column| {
+        let sql = format!(
+            "UPDATE {} SET {} = CURRENT_TIMESTAMP WHERE {} = {} AND {} IS NULL",
+            table,
+            column,
+            pk_column,
+            dialect.placeholder(1),
+            column
+        );
+        quote! {
+            pub async fn soft_delete<'a, A>(conn: A, id: &#pk_ty) -> #result<u64>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let query = #sqlx::query(#sql).bind(id);
+                let res = #trace(#sql, query.execute(&mut *conn)).await?;
+                Ok(res.rows_affected())
+            }
+        }
+    });
+
+    let insert = match dialect.numbered {
+        // postgres has no last insert id, the key comes back through RETURNING
+        true => {
+            let sql = format!("{} RETURNING {}", insert_sql, pk_column);
+            quote! {
+                pub async fn insert<'a, A>(&self, conn: A) -> #result<#pk_ty>
+                where
+                    A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+                {
+                    let mut conn = conn.acquire().await?;
+                    let query = #sqlx::query(#sql)
+                        #(.bind(&self.#insert_binds))*;
+                    let row = #trace(#sql, query.fetch_one(&mut *conn)).await?;
+                    #sqlx::Row::try_get(&row, 0)
+                }
+            }
+        }
+        false => quote! {
+            pub async fn insert<'a, A>(
+                &self,
+                conn: A,
+            ) -> #result<<#sqlx::#db as #sqlx::Database>::QueryResult>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let query = #sqlx::query(#insert_sql)
+                    #(.bind(&self.#insert_binds))*;
+                #trace(#insert_sql, query.execute(&mut *conn)).await
+            }
+        },
+    };
+
+    let gen = quote! {
+        #ast
+
+        impl<'r> #sqlx::FromRow<'r, #sqlx::#row> for #name {
+            fn from_row(row: &'r #sqlx::#row) -> #result<Self> {
+                Ok(Self {
+                    #(#getters,)*
+                })
+            }
+        }
+
+        impl #name {
+            #insert
+
+            pub async fn get_by_id<'a, A>(conn: A, id: &#pk_ty) -> #result<Option<Self>>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let query = #sqlx::query_as::<_, Self>(#get_sql).bind(id);
+                #trace(#get_sql, query.fetch_optional(&mut *conn)).await
+            }
+
+            pub async fn update<'a, A>(&self, conn: A) -> #result<u64>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let query = #sqlx::query(#update_sql)
+                    #(.bind(&self.#update_binds))*
+                    .bind(&self.#pk_ident);
+                let res = #trace(#update_sql, query.execute(&mut *conn)).await?;
+                Ok(res.rows_affected())
+            }
+
+            pub async fn delete<'a, A>(conn: A, id: &#pk_ty) -> #result<u64>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let query = #sqlx::query(#delete_sql).bind(id);
+                let res = #trace(#delete_sql, query.execute(&mut *conn)).await?;
+                Ok(res.rows_affected())
+            }
+
+            #soft_delete
+
+            pub async fn list<'a, A>(
+                conn: A,
+                page: &#krate::Pagination,
+            ) -> #result<(Vec<Self>, usize)>
+            where
+                A: #sqlx::Acquire<'a, Database = #sqlx::#db>,
+            {
+                let mut conn = conn.acquire().await?;
+                let total: i64 = #trace(
+                    #count_sql,
+                    #sqlx::query_scalar(#count_sql).fetch_one(&mut *conn),
+                )
+                .await?;
+                let query = #sqlx::query_as::<_, Self>(#list_sql)
+                    .bind(page.take())
+                    .bind(page.skip());
+                let items = #trace(#list_sql, query.fetch_all(&mut *conn)).await?;
+                Ok((items, total as usize))
+            }
+        }
+    };
+    Ok(gen)
+}
@@ -0,0 +1,113 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::punctuated::Punctuated;
+
+// #[sql_test(db = "postgres", migrations = "migrations", fixtures("tests/fixtures/users.yaml"))]
+// async fn test_users(db: TestDb) { ... }
+//
+// Expands to a `#[tokio::test]` running the body against a database of its own,
+// see `testing::run` in util_postgres, util_mysql and util_sqlite.
+#[proc_macro_attribute]
+pub fn sql_test(param: TokenStream, input: TokenStream) -> TokenStream {
+    let mut args = Args::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    syn::parse_macro_input!(param with parser);
+
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+
+    match impl_sql_test(&args, ast) {
+        Ok(v) => v.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    db: Option<String>,
+    migrations: Option<String>,
+    fixtures: Vec<String>,
+}
+
+impl Args {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("db") {
+            self.db = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+        } else if meta.path.is_ident("migrations") {
+            self.migrations = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+        } else if meta.path.is_ident("fixtures") {
+            let content;
+            syn::parenthesized!(content in meta.input);
+            let paths = Punctuated::<syn::LitStr, syn::Token![,]>::parse_terminated(&content)?;
+            self.fixtures.extend(paths.iter().map(|v| v.value()));
+        } else {
+            return Err(meta.error("expected db, migrations or fixtures"));
+        }
+        Ok(())
+    }
+}
+
+fn krate(db: &str) -> syn::Result<syn::Ident> {
+    let res = match db {
+        "postgres" => "util_postgres",
+        "mysql" => "util_mysql",
+        "sqlite" => "util_sqlite",
+        other => {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                format!(
+                    "unsupported db {}, expected postgres, mysql or sqlite",
+                    other
+                ),
+            ))
+        }
+    };
+    Ok(syn::Ident::new(res, Span::call_site()))
+}
+
+fn impl_sql_test(args: &Args, mut ast: syn::ItemFn) -> syn::Result<TokenStream2> {
+    if ast.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            ast.sig.fn_token,
+            "sql_test only supports async functions",
+        ));
+    }
+    if ast.sig.inputs.len() > 1 {
+        return Err(syn::Error::new_spanned(
+            &ast.sig.inputs,
+            "sql_test takes at most one argument, the TestDb",
+        ));
+    }
+
+    let krate = krate(args.db.as_deref().unwrap_or("postgres"))?;
+    let migrations = args.migrations.as_ref().map(|v| quote!(.migrations(#v)));
+    let fixtures = args.fixtures.iter();
+
+    let attrs = std::mem::take(&mut ast.attrs);
+    let vis = ast.vis.clone();
+    let name = ast.sig.ident.clone();
+    let inner = syn::Ident::new("__sql_test_inner", Span::call_site());
+    ast.sig.ident = inner.clone();
+    ast.vis = syn::Visibility::Inherited;
+    let call = match ast.sig.inputs.is_empty() {
+        true => quote!(|_| #inner()),
+        false => quote!(#inner),
+    };
+
+    let gen = quote! {
+        #(#attrs)*
+        #[::tokio::test]
+        #vis async fn #name() {
+            #ast
+
+            #krate::testing::run(
+                #krate::testing::TestOptions::new()
+                    #migrations
+                    #(.fixture(#fixtures))*,
+                #call,
+            )
+            .await
+        }
+    };
+    Ok(gen)
+}
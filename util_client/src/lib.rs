@@ -0,0 +1,117 @@
+pub use reqwest;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Serialize};
+use util_error::{BasicResult, ErrorKind, IntoBasicResult};
+use util_response::prelude::*;
+
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_client(reqwest::Client::new(), base_url)
+    }
+
+    pub fn with_client(inner: reqwest::Client, base_url: impl Into<String>) -> Self {
+        Self {
+            inner,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            token: None,
+        }
+    }
+
+    pub fn bearer(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let url = format!("{}/{}", self.base_url, path.trim_start_matches('/'));
+        let req = self.inner.request(method, url);
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    pub async fn get<D>(&self, path: &str) -> BasicResult<D>
+    where
+        D: DeserializeOwned,
+    {
+        send(self.request(Method::GET, path)).await
+    }
+
+    pub async fn get_page<D>(&self, path: &str, page: &Pagination) -> BasicResult<(D, usize)>
+    where
+        D: DeserializeOwned,
+    {
+        let (data, total) = send_with_total(self.request(Method::GET, path).query(page)).await?;
+        Ok((data, total.unwrap_or_default()))
+    }
+
+    pub async fn post<B, D>(&self, path: &str, body: &B) -> BasicResult<D>
+    where
+        B: Serialize + ?Sized,
+        D: DeserializeOwned,
+    {
+        send(self.request(Method::POST, path).json(body)).await
+    }
+
+    pub async fn put<B, D>(&self, path: &str, body: &B) -> BasicResult<D>
+    where
+        B: Serialize + ?Sized,
+        D: DeserializeOwned,
+    {
+        send(self.request(Method::PUT, path).json(body)).await
+    }
+
+    pub async fn patch<B, D>(&self, path: &str, body: &B) -> BasicResult<D>
+    where
+        B: Serialize + ?Sized,
+        D: DeserializeOwned,
+    {
+        send(self.request(Method::PATCH, path).json(body)).await
+    }
+
+    pub async fn delete<D>(&self, path: &str) -> BasicResult<D>
+    where
+        D: DeserializeOwned,
+    {
+        send(self.request(Method::DELETE, path)).await
+    }
+}
+
+pub async fn send<D>(req: RequestBuilder) -> BasicResult<D>
+where
+    D: DeserializeOwned,
+{
+    send_with_total(req).await.map(|(data, _)| data)
+}
+
+pub async fn send_with_total<D>(req: RequestBuilder) -> BasicResult<(D, Option<usize>)>
+where
+    D: DeserializeOwned,
+{
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status == StatusCode::REQUEST_TIMEOUT {
+        return Err(ErrorKind::Timeout);
+    }
+    let bytes = resp.bytes().await?;
+
+    // error bodies are served as text/html, so parse by content instead of header
+    match serde_json::from_slice::<Response<D, String>>(&bytes) {
+        Ok(v) => v.into_result_with_total(),
+        Err(e) => Err(anyhow::anyhow!(
+            "invalid response, status: {}, error: {}, body: {}",
+            status,
+            e,
+            String::from_utf8_lossy(&bytes)
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,357 @@
+use config::{Environment, File};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use util_error::BasicResult;
+
+const DEFAULT_DIR: &str = "config";
+const DEFAULT_PREFIX: &str = "APP";
+const DEFAULT_PROFILE: &str = "dev";
+
+// A config section owned by one util_* crate, e.g. `[redis]` for util_redis
+pub trait Section: DeserializeOwned {
+    const NAME: &'static str;
+}
+
+// Wraps credentials so they never end up in logs through `Debug`/`Display`
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(v: T) -> Self {
+        Self(v)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(v: T) -> Self {
+        Self(v)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("******")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("******")
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Secret<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(Self)
+    }
+}
+
+impl<T> Serialize for Secret<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str("******")
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // Backoff before the given retry, counting from 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ms = self.initial_backoff_ms as f64 * self.multiplier.powi(retry as i32 - 1);
+        Duration::from_millis(ms.min(self.max_backoff_ms as f64) as u64)
+    }
+}
+
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, name: &str, f: F) -> BasicResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = BasicResult<T>>,
+{
+    retry_if(policy, name, f, |_| true).await
+}
+
+pub async fn retry_if<T, F, Fut, P>(
+    policy: &RetryPolicy,
+    name: &str,
+    mut f: F,
+    mut retryable: P,
+) -> BasicResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = BasicResult<T>>,
+    P: FnMut(&util_error::ErrorKind) -> bool,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < policy.max_attempts && retryable(&e) => {
+                let backoff = policy.backoff(attempt);
+                log::warn!(
+                    "{} attempt {} failed, retry in {:?}, error: {}",
+                    name,
+                    attempt,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub struct SettingsBuilder {
+    dir: PathBuf,
+    env_prefix: String,
+    profile: Option<String>,
+    files: Vec<PathBuf>,
+    dotenv: bool,
+}
+
+impl Default for SettingsBuilder {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from(DEFAULT_DIR),
+            env_prefix: DEFAULT_PREFIX.to_string(),
+            profile: None,
+            files: Vec::new(),
+            dotenv: true,
+        }
+    }
+}
+
+impl SettingsBuilder {
+    // Directory holding `default.{toml,yaml}` and `{profile}.{toml,yaml}`
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    // Env vars look like `APP_REDIS__HOST` for prefix `APP`
+    pub fn env_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.env_prefix = prefix.into();
+        self
+    }
+
+    // Defaults to `{PREFIX}_PROFILE`, then `dev`
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    // Extra required file, merged after the profile overlay
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.files.push(path.into());
+        self
+    }
+
+    pub fn dotenv(mut self, enable: bool) -> Self {
+        self.dotenv = enable;
+        self
+    }
+
+    pub fn build(self) -> BasicResult<Settings> {
+        if self.dotenv {
+            if let Err(e) = dotenv::dotenv() {
+                log::debug!("skip .env, error: {}", e);
+            }
+        }
+
+        let profile = self
+            .profile
+            .or_else(|| std::env::var(format!("{}_PROFILE", self.env_prefix)).ok())
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+        let mut builder = config::Config::builder()
+            .add_source(File::from(self.dir.join("default")).required(false))
+            .add_source(File::from(self.dir.join(&profile)).required(false));
+        for f in self.files {
+            builder = builder.add_source(File::from(f));
+        }
+        let inner = builder
+            .add_source(
+                Environment::with_prefix(&self.env_prefix)
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+
+        log::info!("config loaded, profile: {}", profile);
+        Ok(Settings { inner, profile })
+    }
+}
+
+pub struct Settings {
+    inner: config::Config,
+    profile: String,
+}
+
+impl Settings {
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder::default()
+    }
+
+    pub fn load() -> BasicResult<Self> {
+        Self::builder().build()
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    pub fn get<T>(&self) -> BasicResult<T>
+    where
+        T: Section,
+    {
+        self.section(T::NAME)
+    }
+
+    pub fn section<T>(&self, name: &str) -> BasicResult<T>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(self.inner.get::<T>(name)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Redis {
+        host: String,
+        port: u16,
+        password: Option<Secret<String>>,
+    }
+
+    impl Section for Redis {
+        const NAME: &'static str = "redis";
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(10), Duration::from_millis(10_000));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            ..Default::default()
+        };
+        let mut calls = 0;
+        let res = retry(&policy, "test", || {
+            calls += 1;
+            let n = calls;
+            async move {
+                if n < 3 {
+                    Err(anyhow::anyhow!("not yet").into())
+                } else {
+                    Ok(n)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 3);
+
+        calls = 0;
+        let res: BasicResult<()> = retry(&RetryPolicy::none(), "test", || {
+            calls += 1;
+            async { Err(anyhow::anyhow!("never").into()) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_secret_debug() {
+        let v = Redis {
+            host: "localhost".to_string(),
+            port: 6379,
+            password: Some(Secret::new("pwd".to_string())),
+        };
+        assert_eq!(
+            format!("{:?}", v),
+            r#"Redis { host: "localhost", port: 6379, password: Some(******) }"#
+        );
+    }
+
+    #[test]
+    fn test_profile_overlay_and_env() {
+        let dir = std::env::temp_dir().join("util_config_test_profile_overlay");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("default.toml"),
+            "[redis]\nhost = \"localhost\"\nport = 6379\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("prod.yaml"), "redis:\n  host: redis.prod\n").unwrap();
+        std::env::set_var("UTILTEST_REDIS__PASSWORD", "pwd");
+
+        let settings = Settings::builder()
+            .dir(&dir)
+            .env_prefix("UTILTEST")
+            .profile("prod")
+            .dotenv(false)
+            .build()
+            .unwrap();
+        let redis = settings.get::<Redis>().unwrap();
+        assert_eq!(settings.profile(), "prod");
+        assert_eq!(redis.host, "redis.prod");
+        assert_eq!(redis.port, 6379);
+        assert_eq!(redis.password.unwrap().expose(), "pwd");
+    }
+}
@@ -20,13 +20,13 @@ pub trait ToDateTime {
 
 impl FormatDateTime for DateTime<Utc> {
     fn to_rfc3339(&self) -> String {
-        DateTime::<Local>::from(self.clone())
+        DateTime::<Local>::from(*self)
             .format(FORMAT_RFC3339)
             .to_string()
     }
 
     fn to_default(&self) -> String {
-        DateTime::<Local>::from(self.clone())
+        DateTime::<Local>::from(*self)
             .format(FORMAT_DEFAULT)
             .to_string()
     }
@@ -0,0 +1,240 @@
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Concurrency {
+    // One message at a time, in arrival order
+    #[default]
+    Sequential,
+    // Up to n handlers at once, finishing in any order
+    Parallel(usize),
+    // Up to n handlers at once, but one at a time per key so each key keeps its
+    // order, e.g. the pub/sub channel or the record key
+    Keyed(usize),
+}
+
+impl Concurrency {
+    fn max(&self) -> usize {
+        match self {
+            Concurrency::Sequential => 1,
+            Concurrency::Parallel(n) | Concurrency::Keyed(n) => (*n).max(1),
+        }
+    }
+}
+
+// Called with the key of the message and what the handler returned
+pub type ErrorHook<E> = Arc<dyn Fn(&str, E) + Send + Sync>;
+
+pub struct HandlerOptions<E> {
+    pub concurrency: Concurrency,
+    pub on_error: ErrorHook<E>,
+}
+
+impl<E> Clone for HandlerOptions<E> {
+    fn clone(&self) -> Self {
+        Self {
+            concurrency: self.concurrency,
+            on_error: self.on_error.clone(),
+        }
+    }
+}
+
+impl<E: Display> Default for HandlerOptions<E> {
+    fn default() -> Self {
+        Self {
+            concurrency: Default::default(),
+            on_error: Arc::new(|key, e| log::error!("handler for {} failed, error: {}", key, e)),
+        }
+    }
+}
+
+impl<E: Display> HandlerOptions<E> {
+    pub fn new(concurrency: Concurrency) -> Self {
+        Self {
+            concurrency,
+            ..Default::default()
+        }
+    }
+
+    pub fn on_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str, E) + Send + Sync + 'static,
+    {
+        self.on_error = Arc::new(f);
+        self
+    }
+}
+
+// Runs handlers inside the task reading the messages. It only takes a new
+// message while fewer than the concurrency limit are running or queued, so a
+// slow handler holds up reading instead of piling messages up in memory
+pub struct Dispatcher<M, F, E> {
+    f: F,
+    key: fn(&M) -> String,
+    opts: HandlerOptions<E>,
+    running: FuturesUnordered<BoxFuture<'static, (String, Result<(), E>)>>,
+    // keys with a running handler, in keyed mode
+    busy: HashSet<String>,
+    queued: VecDeque<(String, M)>,
+}
+
+impl<M, F, Fut, E> Dispatcher<M, F, E>
+where
+    F: FnMut(M) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+{
+    pub fn new(opts: HandlerOptions<E>, key: fn(&M) -> String, f: F) -> Self {
+        Self {
+            f,
+            key,
+            opts,
+            running: Default::default(),
+            busy: Default::default(),
+            queued: Default::default(),
+        }
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.running.len() + self.queued.len() < self.opts.concurrency.max()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    pub fn push(&mut self, msg: M) {
+        self.queued.push_back(((self.key)(&msg), msg));
+        self.start();
+    }
+
+    // Waits for one handler to finish, pending forever when none runs
+    pub async fn next_done(&mut self) {
+        let Some((key, res)) = self.running.next().await else {
+            return futures::future::pending().await;
+        };
+        self.busy.remove(&key);
+        if let Err(e) = res {
+            (self.opts.on_error)(&key, e);
+        }
+        self.start();
+    }
+
+    // Lets every running and queued handler finish
+    pub async fn drain(&mut self) {
+        while !self.running.is_empty() {
+            self.next_done().await;
+        }
+    }
+
+    fn start(&mut self) {
+        let keyed = matches!(self.opts.concurrency, Concurrency::Keyed(_));
+        let mut i = 0;
+        while i < self.queued.len() && self.running.len() < self.opts.concurrency.max() {
+            if keyed && self.busy.contains(&self.queued[i].0) {
+                i += 1;
+                continue;
+            }
+            let (key, msg) = self.queued.remove(i).unwrap();
+            if keyed {
+                self.busy.insert(key.clone());
+            }
+            let fut = (self.f)(msg);
+            self.running.push(Box::pin(async move { (key, fut.await) }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    type Msg = (&'static str, u32);
+
+    fn by_name(v: &Msg) -> String {
+        v.0.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_keyed_order() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let mut dispatcher = Dispatcher::new(
+            HandlerOptions::<String>::new(Concurrency::Keyed(4)),
+            by_name,
+            move |v: Msg| {
+                let log = log.clone();
+                async move {
+                    // the first message of `a` is the slowest
+                    if v == ("a", 1) {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                    log.lock().unwrap().push(v);
+                    Ok(())
+                }
+            },
+        );
+        for v in [("a", 1), ("a", 2), ("b", 1)] {
+            assert!(dispatcher.has_room());
+            dispatcher.push(v);
+        }
+        dispatcher.drain().await;
+        assert_eq!(*seen.lock().unwrap(), vec![("b", 1), ("a", 1), ("a", 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_bound() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (r, p) = (running.clone(), peak.clone());
+        let mut dispatcher = Dispatcher::new(
+            HandlerOptions::<String>::new(Concurrency::Parallel(2)),
+            by_name,
+            move |_: Msg| {
+                let (r, p) = (r.clone(), p.clone());
+                async move {
+                    p.fetch_max(r.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    r.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            },
+        );
+        for i in 0..6 {
+            while !dispatcher.has_room() {
+                dispatcher.next_done().await;
+            }
+            dispatcher.push(("a", i));
+        }
+        assert!(!dispatcher.has_room());
+        dispatcher.drain().await;
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(running.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_reports_errors() {
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let log = failed.clone();
+        let opts = HandlerOptions::new(Concurrency::Keyed(2))
+            .on_error(move |key, e: String| log.lock().unwrap().push(format!("{}: {}", key, e)));
+        let mut dispatcher = Dispatcher::new(opts, by_name, |v: Msg| async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            match v.1 {
+                0 => Ok(()),
+                _ => Err(format!("bad {}", v.1)),
+            }
+        });
+        dispatcher.push(("a", 0));
+        dispatcher.push(("b", 1));
+        dispatcher.drain().await;
+        assert!(dispatcher.is_idle());
+        assert_eq!(*failed.lock().unwrap(), vec!["b: bad 1".to_string()]);
+    }
+}
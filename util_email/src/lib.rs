@@ -1,69 +1,124 @@
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::{
-    transport::smtp::{response::Response, Error},
-    Message, SmtpTransport, Transport,
-};
+use lettre::{transport::smtp::response::Response, Message, SmtpTransport, Transport};
 use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use util_config::{RetryPolicy, Secret, Section};
+use util_error::{business_error, validate_error, BasicResult};
 
-static mut CONFIG: OnceCell<Config> = OnceCell::new();
-static MAILER: OnceCell<SmtpTransport> = OnceCell::new();
+static DEFAULT_MAILER: OnceCell<Mailer> = OnceCell::new();
+static READY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub from: String,
+    pub pwd: Secret<String>,
+    pub relay: String,
+    pub port: u16,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl Section for Config {
+    const NAME: &'static str = "email";
+}
 
 #[derive(Clone)]
-struct Config {
+pub struct Mailer {
+    transport: SmtpTransport,
     from: String,
-    pwd: String,
-    relay: String,
-    port: u16,
 }
 
-pub async fn init(from: &str, pwd: &str, relay: &str, port: u16) {
-    unsafe {
-        CONFIG.get_or_init(|| Config {
-            from: from.to_string(),
-            pwd: pwd.to_string(),
-            relay: relay.to_string(),
-            port,
-        });
+impl Mailer {
+    pub fn new(cfg: &Config) -> BasicResult<Self> {
+        let creds = Credentials::new(cfg.from.clone(), cfg.pwd.expose().clone());
+        let transport = SmtpTransport::relay(&cfg.relay)?
+            .port(cfg.port)
+            .credentials(creds)
+            .build();
+
+        Ok(Self {
+            transport,
+            from: cfg.from.clone(),
+        })
     }
 
-    match mailer().test_connection() {
-        Ok(v) => {
-            if v {
-                log::info!("email init success");
+    // Same as `new`, but waits until the relay accepts a connection
+    pub async fn connect(cfg: &Config) -> BasicResult<Self> {
+        let res = Self::new(cfg)?;
+        util_config::retry(&cfg.retry, "email connect", || async {
+            if res.transport.test_connection()? {
+                Ok(())
             } else {
-                panic!("mail init failed, the connection not connected");
+                Err(business_error!(
+                    "mail connect failed, the connection not connected"
+                ))
             }
-        }
-        Err(e) => {
-            panic!("mail init failed, error: {}", e)
-        }
+        })
+        .await?;
+        Ok(res)
     }
-}
 
-pub fn mailer() -> &'static SmtpTransport {
-    MAILER.get_or_init(|| {
-        let cfg = unsafe { CONFIG.get_unchecked() };
-        let creds = Credentials::new(cfg.from.clone(), cfg.pwd.clone());
-        let res = SmtpTransport::relay(&cfg.relay)
-            .unwrap()
-            .port(cfg.port)
-            .credentials(creds)
-            .build();
+    pub fn transport(&self) -> &SmtpTransport {
+        &self.transport
+    }
+
+    pub async fn send(&self, to: &str, subject: &str, body: &str) -> BasicResult<Response> {
+        let to_name = to.split('@').next().unwrap_or("reciver");
+        let email = Message::builder()
+            .from(
+                format!("evolve.publisher <{}>", self.from)
+                    .parse()
+                    .map_err(|e| business_error!(format!("invalid from address, error: {}", e)))?,
+            )
+            // .reply_to("Yuin <yuin@domain.tld>".parse().unwrap())
+            .to(format!("{} <{}>", to_name, to)
+                .parse()
+                .map_err(|e| validate_error!(format!("invalid to address, error: {}", e)))?)
+            .subject(subject)
+            .body(String::from(body))
+            .map_err(|e| business_error!(e))?;
 
-        res
+        Ok(self.transport.send(&email)?)
+    }
+}
+
+pub async fn init(from: &str, pwd: &str, relay: &str, port: u16) -> BasicResult<()> {
+    init_with_config(Config {
+        from: from.to_string(),
+        pwd: Secret::new(pwd.to_string()),
+        relay: relay.to_string(),
+        port,
+        retry: RetryPolicy::default(),
     })
+    .await
+}
+
+pub async fn init_with_config(cfg: Config) -> BasicResult<()> {
+    let mailer = Mailer::connect(&cfg).await?;
+    if DEFAULT_MAILER.set(mailer).is_err() {
+        log::warn!("email already initialized, keep the existing mailer");
+    }
+
+    READY.store(true, Ordering::Relaxed);
+    log::info!("email init success");
+    Ok(())
+}
+
+pub fn ready() -> bool {
+    READY.load(Ordering::Relaxed)
+}
+
+pub fn default_mailer() -> BasicResult<&'static Mailer> {
+    DEFAULT_MAILER
+        .get()
+        .ok_or_else(|| business_error!("email not initialized"))
+}
+
+pub fn mailer() -> BasicResult<&'static SmtpTransport> {
+    Ok(default_mailer()?.transport())
 }
 
-pub async fn send(to: &str, subject: &str, body: &str) -> Result<Response, Error> {
-    let from = &unsafe { CONFIG.get_unchecked() }.from;
-    let to_name = to.split("@").next().unwrap_or("reciver");
-    let email = Message::builder()
-        .from(format!("evolve.publisher <{}>", from).parse().unwrap())
-        // .reply_to("Yuin <yuin@domain.tld>".parse().unwrap())
-        .to(format!("{} <{}>", to_name, to).parse().unwrap())
-        .subject(subject)
-        .body(String::from(body))
-        .unwrap();
-
-    mailer().send(&email)
+pub async fn send(to: &str, subject: &str, body: &str) -> BasicResult<Response> {
+    default_mailer()?.send(to, subject, body).await
 }
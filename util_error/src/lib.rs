@@ -7,6 +7,7 @@ pub enum ErrCode {
     Unauthorized = 40100000,
     Hint = 45200000,
     Timeout = 40800000,
+    TooManyRequests = 42900000,
     Other = 60000000,
 }
 
@@ -27,10 +28,22 @@ pub enum ErrorKind {
     #[error("timeout")]
     Timeout,
 
-    #[cfg(feature = "postgres")]
+    #[error("[ err_code: {} ] too many requests, retry after {}s: {}",.err_code,.retry_after,.msg)]
+    TooManyRequests {
+        msg: String,
+        err_code: usize,
+        // seconds, sent as `Retry-After`
+        retry_after: u64,
+    },
+
+    #[cfg(feature = "sqlx")]
     #[error(transparent)]
     Sqlx(#[from] sqlx::Error),
 
+    #[cfg(feature = "sqlx")]
+    #[error(transparent)]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+
     #[cfg(feature = "redis")]
     #[error(transparent)]
     Redis(#[from] redis::RedisError),
@@ -68,11 +81,74 @@ pub enum ErrorKind {
     #[cfg(feature = "email")]
     #[error(transparent)]
     SMTP(#[from] lettre::transport::smtp::Error),
+
+    #[cfg(feature = "reqwest")]
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+
+    #[cfg(feature = "config")]
+    #[error(transparent)]
+    Config(#[from] config::ConfigError),
+}
+
+impl ErrorKind {
+    // Rebuild the error a remote service reported, keeping its kind by err_code range
+    pub fn from_err_code(msg: impl Into<String>, err_code: usize) -> Self {
+        let msg = msg.into();
+        match err_code {
+            40000000..=40099999 => ErrorKind::Validate { msg, err_code },
+            40100000..=40199999 => ErrorKind::Unauthorized { msg, err_code },
+            40800000..=40899999 => ErrorKind::Timeout,
+            42900000..=42999999 => ErrorKind::TooManyRequests {
+                msg,
+                err_code,
+                retry_after: 0,
+            },
+            45200000..=45299999 => ErrorKind::Hint { msg, err_code },
+            _ => ErrorKind::Business { msg, err_code },
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+pub trait IntoBasicResult<D> {
+    fn into_result(self) -> BasicResult<D>;
+    fn into_result_with_total(self) -> BasicResult<(D, Option<usize>)>;
+}
+
+#[cfg(feature = "json")]
+impl<D, M> IntoBasicResult<D> for Response<D, M>
+where
+    D: serde::de::DeserializeOwned,
+    M: AsRef<str>,
+{
+    fn into_result(self) -> BasicResult<D> {
+        self.into_result_with_total().map(|(data, _)| data)
+    }
+
+    fn into_result_with_total(self) -> BasicResult<(D, Option<usize>)> {
+        let (data, total, msg, err_code) = self.into_parts();
+        let msg = msg.as_ref().map(|v| v.as_ref().to_string());
+        match (data, msg, err_code) {
+            (_, msg, Some(err_code)) => {
+                Err(ErrorKind::from_err_code(msg.unwrap_or_default(), err_code))
+            }
+            (Some(data), _, None) => Ok((data, total)),
+            (None, Some(msg), None) => Err(anyhow::anyhow!(msg).into()),
+            // `data: null` is skipped on the wire, so unit-like payloads arrive empty
+            (None, None, None) => serde_json::from_value::<D>(serde_json::Value::Null)
+                .map(|data| (data, total))
+                .map_err(|_| anyhow::anyhow!("response has neither data nor msg").into()),
+        }
+    }
 }
 
 #[cfg(feature = "actix-web")]
 use actix_web::{http::header::ContentType, HttpResponse};
-use util_response::{msg, prelude::*};
+#[cfg(feature = "actix-web")]
+use util_response::msg;
+#[cfg(feature = "json")]
+use util_response::prelude::*;
 #[cfg(feature = "actix-web")]
 impl actix_web::error::ResponseError for ErrorKind {
     fn status_code(&self) -> actix_web::http::StatusCode {
@@ -82,13 +158,17 @@ impl actix_web::error::ResponseError for ErrorKind {
             ErrorKind::Unauthorized { .. } => actix_web::http::StatusCode::UNAUTHORIZED,
             ErrorKind::Hint { .. } => actix_web::http::StatusCode::BAD_REQUEST,
             ErrorKind::Timeout => actix_web::http::StatusCode::REQUEST_TIMEOUT,
+            ErrorKind::TooManyRequests { .. } => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
             _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
+        let mut res = HttpResponse::build(self.status_code());
+        if let ErrorKind::TooManyRequests { retry_after, .. } = self {
+            res.insert_header(("retry-after", retry_after.to_string()));
+        }
+        res.insert_header(ContentType::html())
             .insert_header(("access-control-allow-origin", "*"))
             .insert_header(("access-control-allow-headers", "authorization,content-type"))
             .insert_header((
@@ -100,7 +180,8 @@ impl actix_web::error::ResponseError for ErrorKind {
                 ErrorKind::Business { msg, err_code }
                 | ErrorKind::Validate { msg, err_code }
                 | ErrorKind::Hint { msg, err_code }
-                | ErrorKind::Unauthorized { msg, err_code } => {
+                | ErrorKind::Unauthorized { msg, err_code }
+                | ErrorKind::TooManyRequests { msg, err_code, .. } => {
                     serde_json::to_string(&msg!(msg, *err_code)).unwrap()
                 }
                 _ => serde_json::to_string(&msg!(self.to_string())).unwrap(),
@@ -172,6 +253,31 @@ macro_rules! hint {
     }};
 }
 
+#[macro_export]
+macro_rules! too_many_requests {
+    ($msg: expr, $retry_after: expr) => {{
+        too_many_requests!(
+            $msg,
+            $retry_after,
+            util_error::ErrCode::TooManyRequests as usize
+        )
+    }};
+
+    ($msg: expr, $retry_after: expr, $err_code: expr) => {{
+        if $err_code < 42900000 || $err_code > 42999999 {
+            panic!("err_code must between 42900000 and 42999999");
+        }
+
+        let res = util_error::ErrorKind::TooManyRequests {
+            msg: $msg.to_string(),
+            err_code: $err_code,
+            retry_after: $retry_after,
+        };
+        log::warn!("{}", res);
+        res
+    }};
+}
+
 #[macro_export]
 macro_rules! unauthorized {
     ($msg: expr) => {{
@@ -182,7 +288,7 @@ macro_rules! unauthorized {
         if $err_code < 40100000 || $err_code > 40199999 {
             panic!("err_code must between 40100000 and 40199999");
         }
-        
+
         let res = util_error::ErrorKind::Unauthorized {
             msg: $msg.to_string(),
             err_code: $err_code,
@@ -191,3 +297,69 @@ macro_rules! unauthorized {
         res
     }};
 }
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Response<Vec<i32>, String> {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_into_result_data() {
+        let res = parse(r#"{"data":[1,2],"total":10}"#).into_result_with_total();
+        assert_eq!(res.unwrap(), (vec![1, 2], Some(10)));
+    }
+
+    #[test]
+    fn test_into_result_validate() {
+        let res = parse(r#"{"msg":"name required","err_code":40000001}"#).into_result();
+        assert!(matches!(
+            res,
+            Err(ErrorKind::Validate { ref msg, err_code: 40000001 }) if msg == "name required"
+        ));
+    }
+
+    #[test]
+    fn test_into_result_ranges() {
+        assert!(matches!(
+            ErrorKind::from_err_code("", 40100000),
+            ErrorKind::Unauthorized { .. }
+        ));
+        assert!(matches!(
+            ErrorKind::from_err_code("", 45200001),
+            ErrorKind::Hint { .. }
+        ));
+        assert!(matches!(
+            ErrorKind::from_err_code("", 40800000),
+            ErrorKind::Timeout
+        ));
+        assert!(matches!(
+            ErrorKind::from_err_code("", 42900001),
+            ErrorKind::TooManyRequests {
+                err_code: 42900001,
+                ..
+            }
+        ));
+        assert!(matches!(
+            ErrorKind::from_err_code("", 50000002),
+            ErrorKind::Business {
+                err_code: 50000002,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_into_result_msg_only() {
+        let res = parse(r#"{"msg":"connection refused"}"#).into_result();
+        assert!(matches!(res, Err(ErrorKind::Anyhow(_))));
+    }
+
+    #[test]
+    fn test_into_result_unit() {
+        let res: Response<(), String> = serde_json::from_str("{}").unwrap();
+        assert!(res.into_result().is_ok());
+    }
+}
@@ -11,6 +11,6 @@ async fn main() -> anyhow::Result<()> {
 
     close_sender.send(()).await?;
     close_done_receiver.await?;
-    println!("{}", "closed");
+    println!("closed");
     Ok(())
 }
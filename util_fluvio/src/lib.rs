@@ -1,9 +1,10 @@
-pub use fluvio::Offset;
+pub use fluvio::{dataplane::record::RecordKey, Offset};
 use fluvio::{
-    dataplane::record::{ConsumerRecord, RecordData, RecordKey},
+    dataplane::record::{ConsumerRecord, RecordData},
     PartitionSelectionStrategy, ProduceOutput,
 };
 use std::collections::HashMap;
+use std::future::Future;
 use tokio::sync::{
     mpsc::{self, Sender},
     oneshot::{self, Receiver},
@@ -11,6 +12,11 @@ use tokio::sync::{
 
 use std::hash::Hash;
 use tokio_stream::StreamExt;
+pub use util_dispatch::Concurrency;
+use util_dispatch::Dispatcher;
+use util_error::{BasicResult, ErrorKind};
+
+pub type HandlerOptions = util_dispatch::HandlerOptions<ErrorKind>;
 
 pub async fn produce<S, K, V>(topic: S, key: K, value: V) -> anyhow::Result<ProduceOutput>
 where
@@ -54,6 +60,34 @@ pub async fn consume<S, F>(
 where
     S: Into<String>,
     F: FnMut(ConsumerRecord) + Send + 'static,
+{
+    let handler = move |record| {
+        callback(record);
+        futures::future::ready(Ok(()))
+    };
+    consume_async(
+        topics_with_partition,
+        offset,
+        HandlerOptions::default(),
+        handler,
+    )
+    .await
+}
+
+// `callback` may await, e.g. a database write. Running handlers are let finish
+// before the loop ends on close.
+// `consumer_with_config` only takes one topic, keep the multi-partition consumer for now
+#[allow(deprecated)]
+pub async fn consume_async<S, F, Fut>(
+    topics_with_partition: Vec<(S, u32)>,
+    offset: Offset,
+    handler: HandlerOptions,
+    callback: F,
+) -> anyhow::Result<(Sender<()>, Receiver<()>)>
+where
+    S: Into<String>,
+    F: FnMut(ConsumerRecord) -> Fut + Send + 'static,
+    Fut: Future<Output = BasicResult<()>> + Send + 'static,
 {
     let conn = fluvio::Fluvio::connect().await?;
     let topics_with_partition = topics_with_partition
@@ -69,18 +103,28 @@ where
     let (close_sender, close_receiver) = oneshot::channel::<()>();
 
     tokio::spawn(async move {
+        let mut dispatcher = Dispatcher::new(handler, record_key, callback);
         'l: loop {
             tokio::select! {
-                Some(Ok(v)) = stream.next() =>{
-                    callback(v)
+                Some(Ok(v)) = stream.next(), if dispatcher.has_room() => {
+                    dispatcher.push(v)
                 }
+                _ = dispatcher.next_done(), if !dispatcher.is_idle() => {}
                 Some(_) = receiver.recv() => {
                     break 'l
                 }
             }
         }
-        close_sender.send(()).unwrap();
+        dispatcher.drain().await;
+        let _ = close_sender.send(());
     });
 
     Ok((sender, close_receiver))
 }
+
+fn record_key(record: &ConsumerRecord) -> String {
+    match record.key() {
+        Some(key) => format!("{}:{}", record.partition(), String::from_utf8_lossy(key)),
+        None => record.partition().to_string(),
+    }
+}
@@ -0,0 +1,216 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use futures::future::BoxFuture;
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use util_error::{business_error, BasicResult, ErrorKind};
+
+// A hung dependency should fail its check, not the probe
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(latency: Duration, res: BasicResult<()>) -> Self {
+        let (status, error) = match res {
+            Ok(()) => (Status::Up, None),
+            Err(e) => (Status::Down, Some(e.to_string())),
+        };
+        Self {
+            status,
+            latency_ms: latency.as_millis() as u64,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    // Down as soon as one check is down
+    pub status: Status,
+    pub checks: BTreeMap<String, Check>,
+}
+
+impl Report {
+    fn new(checks: BTreeMap<String, Check>) -> Self {
+        let status = match checks.values().all(|c| c.status == Status::Up) {
+            true => Status::Up,
+            false => Status::Down,
+        };
+        Self { status, checks }
+    }
+}
+
+type Pending = BoxFuture<'static, (String, Check)>;
+
+fn pending<Fut>(name: impl Into<String>, f: Fut) -> Pending
+where
+    Fut: Future<Output = BasicResult<()>> + Send + 'static,
+{
+    let name = name.into();
+    Box::pin(async move {
+        let start = Instant::now();
+        let res = match tokio::time::timeout(CHECK_TIMEOUT, f).await {
+            Ok(v) => v,
+            Err(_) => Err(ErrorKind::Timeout),
+        };
+        (name, Check::new(start.elapsed(), res))
+    })
+}
+
+#[allow(dead_code)]
+fn not_initialized(name: &str) -> Pending {
+    let msg = format!("{} not initialized", name);
+    pending(name, async move { Err(business_error!(msg)) })
+}
+
+#[allow(unused_macros)]
+macro_rules! sql_checks {
+    ($checks: ident, $krate: ident, $name: literal) => {
+        if $krate::ready() {
+            for (name, pool) in $krate::health::pools() {
+                $checks.push(pending(format!("{}.{}", $name, name), async move {
+                    pool.ping().await.map(|_| ())
+                }));
+            }
+        } else {
+            $checks.push(not_initialized($name));
+        }
+    };
+}
+
+// Runs the checks of every backend enabled by feature, concurrently
+pub async fn check() -> Report {
+    #[allow(unused_mut)]
+    let mut checks: Vec<Pending> = Vec::new();
+
+    #[cfg(feature = "postgres")]
+    sql_checks!(checks, util_postgres, "postgres");
+    #[cfg(feature = "mysql")]
+    sql_checks!(checks, util_mysql, "mysql");
+    #[cfg(feature = "sqlite")]
+    sql_checks!(checks, util_sqlite, "sqlite");
+
+    #[cfg(feature = "redis")]
+    checks.push(match util_redis::ready() {
+        true => pending("redis", async { util_redis::ping().await.map(|_| ()) }),
+        false => not_initialized("redis"),
+    });
+
+    #[cfg(feature = "meilisearch")]
+    checks.push(match util_meilisearch::ready() {
+        true => pending("meilisearch", async {
+            let health = util_meilisearch::client()?.health().await?;
+            match health.status.as_str() {
+                "available" => Ok(()),
+                status => Err(business_error!(format!("meilisearch {}", status))),
+            }
+        }),
+        false => not_initialized("meilisearch"),
+    });
+
+    // the smtp client is blocking, keep it off the actix workers
+    #[cfg(feature = "email")]
+    checks.push(match util_email::ready() {
+        true => pending("smtp", async {
+            let transport = util_email::mailer()?.clone();
+            match tokio::task::spawn_blocking(move || transport.test_connection()).await {
+                Ok(Ok(true)) => Ok(()),
+                Ok(Ok(false)) => Err(business_error!("smtp not connected")),
+                Ok(Err(e)) => Err(e.into()),
+                Err(e) => Err(business_error!(e)),
+            }
+        }),
+        false => not_initialized("smtp"),
+    });
+
+    Report::new(
+        futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .collect(),
+    )
+}
+
+// Prometheus text format of the default registry, pool gauges sampled first
+pub fn gather() -> BasicResult<String> {
+    #[cfg(feature = "postgres")]
+    util_postgres::health::collect_metrics();
+    #[cfg(feature = "mysql")]
+    util_mysql::health::collect_metrics();
+    #[cfg(feature = "sqlite")]
+    util_sqlite::health::collect_metrics();
+
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(|e| business_error!(e))?;
+    String::from_utf8(buf).map_err(|e| business_error!(e))
+}
+
+// 200 when every check is up, 503 otherwise
+pub async fn health() -> HttpResponse {
+    let report = check().await;
+    let status = match report.status {
+        Status::Up => StatusCode::OK,
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    HttpResponse::build(status).json(report)
+}
+
+pub async fn metrics() -> HttpResponse {
+    match gather() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(body),
+        Err(e) => {
+            log::error!("gather metrics failed, error: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Mounts `GET /health` and `GET /metrics`
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/health", web::get().to(health))
+        .route("/metrics", web::get().to(metrics));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report() {
+        let checks = vec![
+            pending("up", async { Ok(()) }),
+            pending("down", async { Err(business_error!("refused")) }),
+        ];
+        let report = Report::new(
+            futures::future::join_all(checks)
+                .await
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(report.status, Status::Down);
+        let down = serde_json::to_value(&report.checks["down"]).unwrap();
+        assert_eq!(down["status"], "down");
+        assert!(down["error"].as_str().unwrap().contains("refused"));
+        assert!(report.checks["up"].error.is_none());
+    }
+}
@@ -1,37 +1,89 @@
 use meilisearch_sdk::client::Client;
 pub use meilisearch_sdk::settings::Settings;
 use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use util_config::{RetryPolicy, Secret, Section};
+use util_error::{business_error, BasicResult};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub address: String,
+    pub api_key: Secret<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+impl Section for Config {
+    const NAME: &'static str = "meilisearch";
+}
+
+static DEFAULT_CLIENT: OnceCell<SearchClient> = OnceCell::new();
+static READY: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
-struct Config {
-    address: String,
-    api_key: String,
+pub struct SearchClient {
+    client: Client,
 }
 
-static mut CONFIG: OnceCell<Config> = OnceCell::new();
-static CLIENT: OnceCell<Client> = OnceCell::new();
+impl SearchClient {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            client: Client::new(cfg.address.clone(), cfg.api_key.expose().clone()),
+        }
+    }
 
-pub async fn init(address: &str, api_key: &str) {
-    unsafe {
-        CONFIG.get_or_init(|| Config {
-            address: address.to_string(),
-            api_key: api_key.to_string(),
+    // Same as `new`, but waits until the server answers get_stats
+    pub async fn connect(cfg: &Config) -> BasicResult<Self> {
+        let res = Self::new(cfg);
+        util_config::retry(&cfg.retry, "meilisearch connect", || async {
+            res.client.get_stats().await?;
+            Ok(())
         })
-    };
+        .await?;
+        Ok(res)
+    }
+}
 
-    match client().get_stats().await {
-        Ok(_status) => {
-            log::info!("meilisearch init success")
-        }
-        Err(e) => {
-            panic!("get_stats failed, error: {e}")
-        }
+impl Deref for SearchClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
     }
 }
 
-pub fn client() -> &'static Client {
-    CLIENT.get_or_init(|| {
-        let cfg = unsafe { CONFIG.get_unchecked() };
-        Client::new(cfg.address.clone(), cfg.api_key.clone())
+pub async fn init(address: &str, api_key: &str) -> BasicResult<()> {
+    init_with_config(Config {
+        address: address.to_string(),
+        api_key: Secret::new(api_key.to_string()),
+        retry: RetryPolicy::default(),
     })
+    .await
+}
+
+pub async fn init_with_config(cfg: Config) -> BasicResult<()> {
+    let client = SearchClient::connect(&cfg).await?;
+    if DEFAULT_CLIENT.set(client).is_err() {
+        log::warn!("meilisearch already initialized, keep the existing client");
+    }
+
+    READY.store(true, Ordering::Relaxed);
+    log::info!("meilisearch init success");
+    Ok(())
+}
+
+pub fn ready() -> bool {
+    READY.load(Ordering::Relaxed)
+}
+
+pub fn default_client() -> BasicResult<&'static SearchClient> {
+    DEFAULT_CLIENT
+        .get()
+        .ok_or_else(|| business_error!("meilisearch not initialized"))
+}
+
+pub fn client() -> BasicResult<&'static Client> {
+    Ok(default_client()?)
 }
@@ -0,0 +1,34 @@
+use crate::{SqlPool, DEFAULT_POOL, POOLS, PRIMARY};
+use std::time::Duration;
+use util_error::BasicResult;
+
+impl SqlPool {
+    // Round trip time, including the wait for a connection
+    pub async fn ping(&self) -> BasicResult<Duration> {
+        Ok(self.pool().ping().await?)
+    }
+}
+
+// The primary pool, if initialized, then registered pools by name
+pub fn pools() -> Vec<(String, &'static SqlPool)> {
+    let mut res = DEFAULT_POOL
+        .get()
+        .map(|pool| vec![(PRIMARY.to_string(), pool)])
+        .unwrap_or_default();
+    let mut named = POOLS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, (pool, _))| (name.clone(), *pool))
+        .collect::<Vec<_>>();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    res.extend(named);
+    res
+}
+
+// Samples pool sizes into the default prometheus registry, call before gathering
+pub fn collect_metrics() {
+    for (_, pool) in pools() {
+        pool.pool().collect_metrics();
+    }
+}
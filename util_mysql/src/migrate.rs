@@ -0,0 +1,42 @@
+use crate::SqlPool;
+use sqlx::migrate::Migrator;
+use util_error::BasicResult;
+pub use util_sql::migrate::{MigrateConfig, MigrationState, MigrationStatus};
+
+// See `util_sql::migrate` for the locking
+pub async fn status(pool: &SqlPool, migrator: &Migrator) -> BasicResult<Vec<MigrationStatus>> {
+    util_sql::migrate::status(pool.pool(), migrator).await
+}
+
+// Applies pending migrations, returns them; with `dry_run` only returns them
+pub async fn up(
+    pool: &SqlPool,
+    migrator: &Migrator,
+    dry_run: bool,
+) -> BasicResult<Vec<MigrationStatus>> {
+    util_sql::migrate::up(pool.pool(), migrator, dry_run).await
+}
+
+// Reverts applied migrations newer than `target`
+pub async fn down(
+    pool: &SqlPool,
+    migrator: &Migrator,
+    target: i64,
+    dry_run: bool,
+) -> BasicResult<Vec<MigrationStatus>> {
+    util_sql::migrate::down(pool.pool(), migrator, target, dry_run).await
+}
+
+pub async fn run_on_startup(pool: &SqlPool, cfg: &MigrateConfig) -> BasicResult<()> {
+    util_sql::migrate::run_on_startup(pool.pool(), cfg).await
+}
+
+// Handles `migrate <up|down <target>|status> [--dry-run]`, returns false for other args
+// so `main` can fall through to starting the service
+pub async fn run_cli<I, S>(pool: &SqlPool, migrator: &Migrator, args: I) -> BasicResult<bool>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    util_sql::migrate::run_cli(pool.pool(), migrator, args).await
+}
@@ -0,0 +1,286 @@
+use crate::{migrate::MigrateConfig, PoolOptions, SqlPool};
+use futures::FutureExt;
+pub use sql_test_derive::sql_test as test;
+use sqlx::mysql::MySqlArguments;
+use sqlx::query::Query;
+use sqlx::{MySql, Transaction};
+use std::future::Future;
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use util_error::{business_error, BasicResult};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+tokio::task_local! {
+    // Makes `default_pool()` return the test database inside `run`
+    pub(crate) static SCOPED_POOL: &'static SqlPool;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TestOptions {
+    migrations: Option<PathBuf>,
+    fixtures: Vec<PathBuf>,
+}
+
+impl TestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Defaults to `migrations` when that directory exists
+    pub fn migrations(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.migrations = Some(dir.into());
+        self
+    }
+
+    // YAML or JSON, tables mapped to rows, loaded in file order
+    pub fn fixture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.fixtures.push(path.into());
+        self
+    }
+}
+
+// A database of its own on the server of `TEST_DATABASE_URL`, or `DATABASE_URL`,
+// dropped on teardown
+#[derive(Clone)]
+pub struct TestDb {
+    pool: &'static SqlPool,
+    admin: SqlPool,
+    name: String,
+}
+
+impl TestDb {
+    pub async fn create(opts: &TestOptions) -> BasicResult<Self> {
+        let url = admin_url()?;
+        let options = PoolOptions {
+            health_check_interval_ms: None,
+            ..Default::default()
+        };
+        let admin = SqlPool::connect_with(
+            &url,
+            &PoolOptions {
+                max_connections: 1,
+                ..options.clone()
+            },
+        )
+        .await?;
+        let name = unique_name();
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(admin.pool())
+            .await?;
+        let connect = options.connect_options(&url)?.database(&name);
+        let pool = SqlPool::from(options.pool_options().connect_with(connect).await?);
+        let pool: &'static SqlPool = Box::leak(Box::new(pool));
+        let res = Self { pool, admin, name };
+        if let Err(e) = res.prepare(opts).await {
+            if let Err(e) = res.teardown().await {
+                log::error!("teardown mysql test database failed, error: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    async fn prepare(&self, opts: &TestOptions) -> BasicResult<()> {
+        self.migrate(opts).await?;
+        for path in opts.fixtures.iter() {
+            self.load_fixture(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate(&self, opts: &TestOptions) -> BasicResult<()> {
+        let dir = match &opts.migrations {
+            Some(v) => v.clone(),
+            None => PathBuf::from(MigrateConfig::default().dir),
+        };
+        if opts.migrations.is_some() || dir.is_dir() {
+            let migrator = sqlx::migrate::Migrator::new(dir.as_path()).await?;
+            migrator.run(self.pool.pool()).await?;
+        }
+        Ok(())
+    }
+
+    pub fn pool(&self) -> &'static SqlPool {
+        self.pool
+    }
+
+    // Name of the database created for this test
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn load_fixture(&self, path: impl AsRef<Path>) -> BasicResult<()> {
+        let path = path.as_ref();
+        let tables = read_fixture(path)?;
+        let mut tx = self.pool.tran().await?;
+        for (table, rows) in tables.iter() {
+            for row in rows.iter() {
+                insert(&mut tx, table, row).await?;
+            }
+        }
+        tx.commit().await?;
+        log::debug!("fixture {:?} loaded", path);
+        Ok(())
+    }
+
+    pub async fn teardown(&self) -> BasicResult<()> {
+        self.pool.close().await;
+        sqlx::query(&format!("DROP DATABASE IF EXISTS {}", self.name))
+            .execute(self.admin.pool())
+            .await?;
+        self.admin.close().await;
+        Ok(())
+    }
+}
+
+impl Deref for TestDb {
+    type Target = SqlPool;
+
+    fn deref(&self) -> &Self::Target {
+        self.pool
+    }
+}
+
+type Fixture = Vec<(String, Vec<serde_yaml::Mapping>)>;
+
+fn read_fixture(path: &Path) -> BasicResult<Fixture> {
+    let text = std::fs::read_to_string(path)?;
+    // JSON is valid YAML, one parser reads both and keeps the table order
+    let invalid =
+        |e: serde_yaml::Error| business_error!(format!("invalid fixture {:?}, error: {}", path, e));
+    let tables: serde_yaml::Mapping = serde_yaml::from_str(&text).map_err(invalid)?;
+    let mut res = Vec::new();
+    for (table, rows) in tables {
+        let table = table
+            .as_str()
+            .ok_or_else(|| {
+                business_error!(format!(
+                    "invalid fixture {:?}, table name must be a string",
+                    path
+                ))
+            })?
+            .to_string();
+        res.push((table, serde_yaml::from_value(rows).map_err(invalid)?));
+    }
+    Ok(res)
+}
+
+async fn insert(
+    tx: &mut Transaction<'_, MySql>,
+    table: &str,
+    row: &serde_yaml::Mapping,
+) -> BasicResult<()> {
+    let mut columns = Vec::new();
+    for k in row.keys() {
+        columns.push(
+            k.as_str()
+                .ok_or_else(|| business_error!(format!("invalid fixture column in {}", table)))?,
+        );
+    }
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    );
+    let mut query = sqlx::query(&sql);
+    for v in row.values() {
+        query = bind(query, v)?;
+    }
+    query.execute(&mut **tx).await?;
+    Ok(())
+}
+
+fn bind<'q>(
+    query: Query<'q, MySql, MySqlArguments>,
+    v: &serde_yaml::Value,
+) -> BasicResult<Query<'q, MySql, MySqlArguments>> {
+    use serde_yaml::Value;
+    Ok(match v {
+        Value::Null => query.bind(None::<String>),
+        Value::Bool(v) => query.bind(*v),
+        Value::Number(v) => match v.as_i64() {
+            Some(i) => query.bind(i),
+            None => query.bind(v.as_f64()),
+        },
+        Value::String(v) => query.bind(v.clone()),
+        // nested values are stored as JSON text
+        other => query.bind(
+            serde_json::to_string(other)
+                .map_err(|e| business_error!(format!("invalid fixture value, error: {}", e)))?,
+        ),
+    })
+}
+
+fn admin_url() -> BasicResult<String> {
+    if let Err(e) = dotenv::dotenv() {
+        log::debug!("skip .env, error: {}", e);
+    }
+    std::env::var("TEST_DATABASE_URL")
+        .or_else(|_| std::env::var("DATABASE_URL"))
+        .map_err(|e| {
+            business_error!(format!(
+                "TEST_DATABASE_URL or DATABASE_URL not found, error: {}",
+                e
+            ))
+        })
+}
+
+fn unique_name() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis())
+        .unwrap_or_default();
+    format!(
+        "test_{}_{}_{}",
+        std::process::id(),
+        millis,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+// What a test body may return, `()` or any `Result` with a debuggable error
+pub trait TestResult {
+    fn check(self);
+}
+
+impl TestResult for () {
+    fn check(self) {}
+}
+
+impl<E> TestResult for Result<(), E>
+where
+    E: std::fmt::Debug,
+{
+    fn check(self) {
+        self.unwrap()
+    }
+}
+
+// Creates the database, runs `f` with it and tears it down, also when `f` panics.
+// `default_pool()`, `conn()` and `tran()` return the test database inside `f`,
+// but not inside tasks spawned from it
+pub async fn run<F, Fut, T>(opts: TestOptions, f: F)
+where
+    F: FnOnce(TestDb) -> Fut,
+    Fut: Future<Output = T>,
+    T: TestResult,
+{
+    let db = TestDb::create(&opts)
+        .await
+        .expect("create mysql test database failed");
+    let res = SCOPED_POOL
+        .scope(db.pool(), AssertUnwindSafe(f(db.clone())).catch_unwind())
+        .await;
+    if let Err(e) = db.teardown().await {
+        log::error!("teardown mysql test database failed, error: {}", e);
+    }
+    match res {
+        Ok(v) => v.check(),
+        Err(e) => std::panic::resume_unwind(e),
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{default_pool, SqlPool};
+use sqlx::{MySql, Transaction};
+use util_error::{business_error, BasicResult};
+use util_sql::tran::TranPool;
+pub use util_sql::tran::{BoxFuture, IsolationLevel, TranOptions};
+
+pub type TranOn<'a> = util_sql::tran::TranOn<'a, SqlPool, MySql>;
+
+impl TranPool<MySql> for SqlPool {
+    fn begin_with<'a>(
+        &'a self,
+        opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<Transaction<'static, MySql>>> {
+        Box::pin(async move {
+            if opts.tenant.is_some() {
+                return Err(business_error!("tenant scoped transactions need postgres"));
+            }
+            let mut conn = self.acquire().await?;
+            // MySQL only accepts SET TRANSACTION before the transaction starts, it then
+            // applies to the next one only
+            if let Some(sql) = opts.set_transaction_sql() {
+                sqlx::query(&sql).execute(&mut *conn).await?;
+            }
+            Ok(Transaction::begin(conn).await?)
+        })
+    }
+}
+
+impl SqlPool {
+    // Commits on `Ok`, rolls back on `Err`, and runs `f` again on deadlocks and serialization failures (40001)
+    pub async fn with_tran<T, F>(&self, opts: &TranOptions, f: F) -> BasicResult<T>
+    where
+        F: for<'c> FnMut(&'c mut Transaction<'static, MySql>) -> BoxFuture<'c, BasicResult<T>>,
+    {
+        util_sql::tran::with_tran(self, opts, f).await
+    }
+}
+
+pub async fn with_tran<T, F>(f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, MySql>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    with_tran_opts(&TranOptions::default(), f).await
+}
+
+pub async fn with_tran_opts<T, F>(opts: &TranOptions, f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, MySql>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    default_pool().await?.with_tran(opts, f).await
+}
+
+// Nests: on `&mut **tx` of an outer transaction `f` runs in a SAVEPOINT, on a
+// pool in a new transaction, see `TranOn`
+pub async fn with_tran_on<'a, T, F>(
+    on: impl Into<TranOn<'a>>,
+    opts: &TranOptions,
+    f: F,
+) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'a, MySql>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    util_sql::tran::with_tran_on(on.into(), opts, f).await
+}
@@ -0,0 +1,95 @@
+use crate::{default_pool, SqlPool};
+use serde::Serialize;
+use sqlx::postgres::{PgListener, PgNotification};
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot::{self, Receiver},
+};
+use util_config::RetryPolicy;
+use util_error::{business_error, BasicResult};
+
+impl SqlPool {
+    // Calls `f` for every notification on `channels`. A lost connection is
+    // reconnected with backoff and the channels are LISTENed again, notifications
+    // sent while disconnected are lost
+    pub async fn listen<F>(
+        &self,
+        channels: &[&str],
+        mut f: F,
+    ) -> BasicResult<(Sender<()>, Receiver<()>)>
+    where
+        F: FnMut(PgNotification) + Send + 'static,
+    {
+        let mut listener = PgListener::connect_with(self.pool()).await?;
+        listener.listen_all(channels.iter().copied()).await?;
+        let channels = channels.join(", ");
+        let (close_sender, mut close_receiver) = mpsc::channel::<()>(1);
+        let (close_done_sender, close_done_receiver) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let retry = RetryPolicy::default();
+            let mut failures = 0;
+            'l: loop {
+                tokio::select! {
+                    res = listener.try_recv() => match res {
+                        Ok(Some(v)) => {
+                            failures = 0;
+                            f(v)
+                        }
+                        // the next `try_recv` reconnects and LISTENs again
+                        Ok(None) => {
+                            log::warn!("postgres listen {} connection lost, reconnecting", channels)
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            let backoff = retry.backoff(failures);
+                            log::error!(
+                                "postgres listen {} failed, retry in {:?}, error: {}",
+                                channels,
+                                backoff,
+                                e
+                            );
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => {}
+                                Some(_) = close_receiver.recv() => break 'l,
+                            }
+                        }
+                    },
+                    Some(_) = close_receiver.recv() => {
+                        break 'l
+                    }
+                }
+            }
+            let _ = close_done_sender.send(());
+        });
+        Ok((close_sender, close_done_receiver))
+    }
+
+    // Payload is sent as JSON, postgres rejects payloads over 8000 bytes
+    pub async fn notify<T>(&self, channel: &str, payload: &T) -> BasicResult<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_string(payload)
+            .map_err(|e| business_error!(format!("invalid notify payload, error: {}", e)))?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(channel)
+            .bind(payload)
+            .execute(self.pool())
+            .await?;
+        Ok(())
+    }
+}
+
+pub async fn listen<F>(channels: &[&str], f: F) -> BasicResult<(Sender<()>, Receiver<()>)>
+where
+    F: FnMut(PgNotification) + Send + 'static,
+{
+    default_pool().await?.listen(channels, f).await
+}
+
+pub async fn notify<T>(channel: &str, payload: &T) -> BasicResult<()>
+where
+    T: Serialize + ?Sized,
+{
+    default_pool().await?.notify(channel, payload).await
+}
@@ -0,0 +1,354 @@
+use crate::{default_pool, SqlPool};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{Postgres, Transaction};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot::{self, Receiver},
+};
+use util_error::{business_error, BasicResult};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub table: String,
+    pub batch_size: i64,
+    pub poll_interval_ms: u64,
+    // A row failing this many publishes is parked, `failed_at` is set and the
+    // relay moves past it. Clear `failed_at` to send it again
+    pub max_attempts: i32,
+    // NOTIFY channel waking the relay up before the next poll
+    pub channel: String,
+    // Rows claimed by a relay that died before publishing are picked up again
+    // after this
+    pub claim_timeout_ms: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            table: "outbox".to_string(),
+            batch_size: 100,
+            poll_interval_ms: 1_000,
+            max_attempts: 10,
+            channel: "outbox".to_string(),
+            claim_timeout_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct OutboxMessage {
+    pub id: i64,
+    // Fluvio topic or redis channel
+    pub topic: String,
+    #[sqlx(rename = "event_key")]
+    pub key: Option<String>,
+    // JSON
+    pub payload: String,
+    pub attempts: i32,
+}
+
+// Delivery is at least once, a crash between publish and mark sent publishes again
+pub trait Publisher: Send + Sync + 'static {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>>;
+}
+
+impl<F, Fut> Publisher for F
+where
+    F: Fn(OutboxMessage) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = BasicResult<()>> + Send + 'static,
+{
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(self(msg.clone()))
+    }
+}
+
+// Publishes the payload to the redis channel named by the topic, through the default client
+#[cfg(feature = "redis")]
+pub struct RedisPublisher;
+
+#[cfg(feature = "redis")]
+impl Publisher for RedisPublisher {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(util_redis::publish(
+            msg.topic.as_str(),
+            msg.payload.as_str(),
+        ))
+    }
+}
+
+#[cfg(feature = "fluvio")]
+pub struct FluvioPublisher;
+
+#[cfg(feature = "fluvio")]
+impl Publisher for FluvioPublisher {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(async move {
+            let key = match &msg.key {
+                Some(v) => util_fluvio::RecordKey::from(v.as_str()),
+                None => util_fluvio::RecordKey::NULL,
+            };
+            util_fluvio::produce(msg.topic.as_str(), key, msg.payload.as_str()).await?;
+            Ok(())
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    cfg: OutboxConfig,
+}
+
+impl Outbox {
+    pub fn new(cfg: OutboxConfig) -> Self {
+        Self { cfg }
+    }
+
+    pub async fn create_table(&self, pool: &SqlPool) -> BasicResult<()> {
+        let table = &self.cfg.table;
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id BIGSERIAL PRIMARY KEY,
+                topic TEXT NOT NULL,
+                event_key TEXT,
+                payload TEXT NOT NULL,
+                attempts INT NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                sent_at TIMESTAMPTZ,
+                claimed_until TIMESTAMPTZ,
+                failed_at TIMESTAMPTZ
+            )",
+            table
+        );
+        sqlx::query(&sql).execute(pool.pool()).await?;
+        let sql = format!(
+            "CREATE INDEX IF NOT EXISTS {0}_pending_idx ON {0} (id) WHERE sent_at IS NULL AND failed_at IS NULL",
+            table
+        );
+        sqlx::query(&sql).execute(pool.pool()).await?;
+        Ok(())
+    }
+
+    // The event is only visible to the relay once `tx` commits
+    pub async fn enqueue<T>(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        topic: &str,
+        key: Option<&str>,
+        payload: &T,
+    ) -> BasicResult<i64>
+    where
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_string(payload)
+            .map_err(|e| business_error!(format!("invalid outbox payload, error: {}", e)))?;
+        let sql = format!(
+            "INSERT INTO {} (topic, event_key, payload) VALUES ($1, $2, $3) RETURNING id",
+            self.cfg.table
+        );
+        let id: i64 = crate::trace::query(
+            &sql,
+            sqlx::query_scalar(&sql)
+                .bind(topic)
+                .bind(key)
+                .bind(payload)
+                .fetch_one(&mut **tx),
+        )
+        .await?;
+        // delivered on commit, dropped on rollback
+        let sql = "SELECT pg_notify($1, $2)";
+        let query = sqlx::query(sql).bind(&self.cfg.channel).bind(topic);
+        crate::trace::query(sql, query.execute(&mut **tx)).await?;
+        Ok(id)
+    }
+
+    // Publishes one batch in id order and returns how many were sent. Stops at the
+    // first failure and retries the row next round, until it failed `max_attempts`
+    // times and is parked. Order only holds with a single relay, with several
+    // another relay claims the rows after a failed one and publishes them
+    pub async fn relay_once<P>(&self, pool: &SqlPool, publisher: &P) -> BasicResult<usize>
+    where
+        P: Publisher,
+    {
+        let table = &self.cfg.table;
+        let messages = self.claim(pool).await?;
+
+        let mut sent = 0;
+        // sent or parked
+        let mut done = 0;
+        for msg in messages.iter() {
+            match publisher.publish(msg).await {
+                Ok(()) => {
+                    let sql = format!(
+                        "UPDATE {} SET sent_at = now(), attempts = attempts + 1 WHERE id = $1",
+                        table
+                    );
+                    sqlx::query(&sql).bind(msg.id).execute(pool.pool()).await?;
+                    sent += 1;
+                }
+                Err(e) => {
+                    let parked = msg.attempts + 1 >= self.cfg.max_attempts;
+                    let sql = format!(
+                        "UPDATE {} SET attempts = attempts + 1, last_error = $2, failed_at = CASE WHEN $3 THEN now() END, claimed_until = NULL WHERE id = $1",
+                        table
+                    );
+                    sqlx::query(&sql)
+                        .bind(msg.id)
+                        .bind(e.to_string())
+                        .bind(parked)
+                        .execute(pool.pool())
+                        .await?;
+                    if !parked {
+                        log::warn!("outbox publish {} failed, error: {}", msg.id, e);
+                        break;
+                    }
+                    log::error!(
+                        "outbox publish {} failed {} times, parked, error: {}",
+                        msg.id,
+                        msg.attempts + 1,
+                        e
+                    );
+                }
+            }
+            done += 1;
+        }
+        // the failed row and those after it go back for the next round
+        let unsent = messages[done..].iter().map(|m| m.id).collect::<Vec<_>>();
+        if !unsent.is_empty() {
+            let sql = format!(
+                "UPDATE {} SET claimed_until = NULL WHERE id = ANY($1)",
+                table
+            );
+            sqlx::query(&sql).bind(unsent).execute(pool.pool()).await?;
+        }
+        Ok(sent)
+    }
+
+    // Claims a batch in a short transaction, so no row lock is held while
+    // publishing. A claim outliving `claim_timeout_ms`, e.g. a publish that hangs,
+    // lets another relay send the row again
+    async fn claim(&self, pool: &SqlPool) -> BasicResult<Vec<OutboxMessage>> {
+        let table = &self.cfg.table;
+        let mut tx = pool.begin().await?;
+        // SKIP LOCKED lets several relays claim at once without double sending
+        let sql = format!(
+            "SELECT id, topic, event_key, payload, attempts FROM {} WHERE sent_at IS NULL AND failed_at IS NULL AND (claimed_until IS NULL OR claimed_until < now()) ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+            table
+        );
+        let messages: Vec<OutboxMessage> = pool
+            .traced(
+                &sql,
+                sqlx::query_as(&sql)
+                    .bind(self.cfg.batch_size)
+                    .fetch_all(&mut *tx),
+            )
+            .await?;
+        if !messages.is_empty() {
+            let sql = format!(
+                "UPDATE {} SET claimed_until = now() + make_interval(secs => $2) WHERE id = ANY($1)",
+                table
+            );
+            pool.traced(
+                &sql,
+                sqlx::query(&sql)
+                    .bind(messages.iter().map(|m| m.id).collect::<Vec<_>>())
+                    .bind(self.cfg.claim_timeout_ms as f64 / 1000.0)
+                    .execute(&mut *tx),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(messages)
+    }
+
+    // Polls every `poll_interval_ms`, or earlier when `enqueue` notifies.
+    // Send to the returned sender to stop, the receiver fires once stopped
+    pub fn relay<P>(&self, pool: &SqlPool, publisher: P) -> (Sender<()>, Receiver<()>)
+    where
+        P: Publisher,
+    {
+        let (sender, mut receiver) = mpsc::channel::<()>(1);
+        let (close_sender, close_receiver) = oneshot::channel::<()>();
+        let outbox = self.clone();
+        let pool = pool.clone();
+        let interval = Duration::from_millis(self.cfg.poll_interval_ms);
+
+        tokio::spawn(async move {
+            let mut listener = outbox.listen(&pool).await;
+            'l: loop {
+                match outbox.relay_once(&pool, &publisher).await {
+                    // a full batch means there may be more, drain before waiting
+                    Ok(n) if n as i64 == outbox.cfg.batch_size => {
+                        if receiver.try_recv().is_ok() {
+                            break 'l;
+                        }
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!("outbox relay failed, error: {}", e),
+                }
+                tokio::select! {
+                    Some(_) = receiver.recv() => {
+                        break 'l
+                    }
+                    _ = wait(&mut listener, interval) => {}
+                }
+            }
+            let _ = close_sender.send(());
+        });
+
+        (sender, close_receiver)
+    }
+
+    async fn listen(&self, pool: &SqlPool) -> Option<PgListener> {
+        let res = async {
+            let mut listener = PgListener::connect_with(pool.pool()).await?;
+            listener.listen(&self.cfg.channel).await?;
+            Ok::<_, sqlx::Error>(listener)
+        }
+        .await;
+        match res {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::warn!("outbox listen failed, fall back to polling, error: {}", e);
+                None
+            }
+        }
+    }
+}
+
+async fn wait(listener: &mut Option<PgListener>, interval: Duration) {
+    match listener {
+        Some(l) => {
+            if let Ok(Err(e)) = tokio::time::timeout(interval, l.recv()).await {
+                log::warn!("outbox listener failed, fall back to polling, error: {}", e);
+                *listener = None;
+            }
+        }
+        None => tokio::time::sleep(interval).await,
+    }
+}
+
+pub async fn enqueue<T>(
+    tx: &mut Transaction<'_, Postgres>,
+    topic: &str,
+    key: Option<&str>,
+    payload: &T,
+) -> BasicResult<i64>
+where
+    T: Serialize + ?Sized,
+{
+    Outbox::default().enqueue(tx, topic, key, payload).await
+}
+
+pub async fn relay<P>(publisher: P) -> BasicResult<(Sender<()>, Receiver<()>)>
+where
+    P: Publisher,
+{
+    Ok(Outbox::default().relay(default_pool().await?, publisher))
+}
@@ -0,0 +1,243 @@
+use crate::{default_pool, SqlPool};
+use serde::Deserialize;
+use sqlx::pool::PoolConnection;
+use sqlx::{PgConnection, Postgres, Transaction};
+use std::ops::{Deref, DerefMut};
+use util_error::{business_error, BasicResult};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TenantMode {
+    // Shared tables guarded by row-level security policies reading the setting,
+    // e.g. `USING (tenant_id = current_setting('app.tenant_id'))`
+    #[default]
+    Setting,
+    // One schema per tenant, first in `search_path`
+    Schema,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TenantOptions {
+    pub mode: TenantMode,
+    pub setting: String,
+    // Schema of a tenant is `schema_prefix` followed by its id
+    pub schema_prefix: String,
+    // Searched after the tenant schema, for tables every tenant shares
+    pub shared_schemas: Vec<String>,
+}
+
+impl Default for TenantOptions {
+    fn default() -> Self {
+        Self {
+            mode: TenantMode::Setting,
+            setting: "app.tenant_id".to_string(),
+            schema_prefix: "tenant_".to_string(),
+            shared_schemas: vec!["public".to_string()],
+        }
+    }
+}
+
+impl TenantOptions {
+    // `set_config` takes both as parameters, nothing user supplied is spliced into SQL
+    fn setting(&self, tenant_id: &str) -> BasicResult<(&str, String)> {
+        if tenant_id.is_empty() {
+            return Err(business_error!("tenant id must not be empty"));
+        }
+        Ok(match self.mode {
+            TenantMode::Setting => (self.setting.as_str(), tenant_id.to_string()),
+            TenantMode::Schema => {
+                let mut schemas =
+                    vec![quote_ident(&format!("{}{}", self.schema_prefix, tenant_id))];
+                schemas.extend(self.shared_schemas.iter().map(|v| quote_ident(v)));
+                ("search_path", schemas.join(", "))
+            }
+        })
+    }
+
+    fn reset_sql(&self) -> String {
+        match self.mode {
+            TenantMode::Setting => format!("RESET {}", self.setting),
+            TenantMode::Schema => "RESET search_path".to_string(),
+        }
+    }
+}
+
+fn quote_ident(v: &str) -> String {
+    format!("\"{}\"", v.replace('"', "\"\""))
+}
+
+// Same as `SET LOCAL`, gone when the transaction ends either way
+pub(crate) async fn set_local(
+    tx: &mut Transaction<'static, Postgres>,
+    opts: &TenantOptions,
+    tenant_id: &str,
+) -> BasicResult<()> {
+    let (name, value) = opts.setting(tenant_id)?;
+    sqlx::query("SELECT set_config($1, $2, true)")
+        .bind(name)
+        .bind(value)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+// A pooled connection scoped to one tenant for its whole session. The setting is
+// reset before the connection goes back to the pool, a connection that fails the
+// reset is closed instead
+pub struct TenantConn {
+    conn: Option<PoolConnection<Postgres>>,
+    reset_sql: String,
+}
+
+impl TenantConn {
+    pub async fn release(mut self) -> BasicResult<()> {
+        match self.conn.take() {
+            Some(conn) => reset(conn, &self.reset_sql).await,
+            None => Ok(()),
+        }
+    }
+}
+
+async fn reset(mut conn: PoolConnection<Postgres>, reset_sql: &str) -> BasicResult<()> {
+    if let Err(e) = sqlx::query(reset_sql).execute(&mut *conn).await {
+        let _ = conn.close().await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+impl Deref for TenantConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("tenant connection released")
+    }
+}
+
+impl DerefMut for TenantConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().expect("tenant connection released")
+    }
+}
+
+impl Drop for TenantConn {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    let reset_sql = std::mem::take(&mut self.reset_sql);
+                    handle.spawn(async move {
+                        if let Err(e) = reset(conn, &reset_sql).await {
+                            log::error!("postgres reset tenant failed, error: {}", e);
+                        }
+                    });
+                }
+                // dropping the detached connection closes it
+                Err(_) => drop(conn.detach()),
+            }
+        }
+    }
+}
+
+impl SqlPool {
+    // Rows visible to `tenant_id` only, see `TenantOptions`
+    pub async fn tenant_tran(
+        &self,
+        tenant_id: &str,
+    ) -> BasicResult<Transaction<'static, Postgres>> {
+        let mut tx = self.tran().await?;
+        set_local(&mut tx, &self.tenant, tenant_id).await?;
+        Ok(tx)
+    }
+
+    pub async fn tenant_conn(&self, tenant_id: &str) -> BasicResult<TenantConn> {
+        let (name, value) = self.tenant.setting(tenant_id)?;
+        let mut conn = self.acquire().await?;
+        sqlx::query("SELECT set_config($1, $2, false)")
+            .bind(name)
+            .bind(value)
+            .execute(&mut *conn)
+            .await?;
+        Ok(TenantConn {
+            conn: Some(conn),
+            reset_sql: self.tenant.reset_sql(),
+        })
+    }
+}
+
+pub async fn tenant_tran(tenant_id: &str) -> BasicResult<Transaction<'static, Postgres>> {
+    default_pool().await?.tenant_tran(tenant_id).await
+}
+
+pub async fn tenant_conn(tenant_id: &str) -> BasicResult<TenantConn> {
+    default_pool().await?.tenant_conn(tenant_id).await
+}
+
+#[cfg(feature = "actix-web")]
+pub use self::actix::TenantId;
+
+#[cfg(feature = "actix-web")]
+mod actix {
+    use actix_web::{dev::Payload, FromRequest, HttpMessage, HttpRequest};
+    use futures::future::{ready, Ready};
+    use sqlx::{Postgres, Transaction};
+    use util_error::{unauthorized, BasicResult, ErrorKind};
+
+    // Put into the request extensions by whatever resolves the tenant, e.g. an
+    // auth middleware, and taken out by handlers as an extractor
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct TenantId(pub String);
+
+    impl TenantId {
+        pub fn set(req: &impl HttpMessage, tenant_id: impl Into<String>) {
+            req.extensions_mut().insert(TenantId(tenant_id.into()));
+        }
+
+        pub fn get(req: &impl HttpMessage) -> Option<TenantId> {
+            req.extensions().get::<TenantId>().cloned()
+        }
+
+        pub async fn tran(&self) -> BasicResult<Transaction<'static, Postgres>> {
+            super::tenant_tran(&self.0).await
+        }
+
+        pub async fn conn(&self) -> BasicResult<super::TenantConn> {
+            super::tenant_conn(&self.0).await
+        }
+    }
+
+    impl FromRequest for TenantId {
+        type Error = ErrorKind;
+        type Future = Ready<Result<Self, Self::Error>>;
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            ready(TenantId::get(req).ok_or_else(|| unauthorized!("tenant not found")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setting() {
+        let opts = TenantOptions::default();
+        assert_eq!(
+            opts.setting("42").unwrap(),
+            ("app.tenant_id", "42".to_string())
+        );
+        assert!(opts.setting("").is_err());
+
+        let opts = TenantOptions {
+            mode: TenantMode::Schema,
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.setting("a\"b").unwrap(),
+            ("search_path", "\"tenant_a\"\"b\", \"public\"".to_string())
+        );
+        assert_eq!(opts.reset_sql(), "RESET search_path");
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{default_pool, SqlPool, DEFAULT_POOL};
+use once_cell::sync::Lazy;
+use sqlx::Postgres;
+use std::future::Future;
+use util_error::BasicResult;
+pub use util_sql::trace::{LogLevel, RowCount, TraceOptions};
+
+static DEFAULT_TRACE: Lazy<TraceOptions> = Lazy::new(Default::default);
+
+impl SqlPool {
+    // Runs `fut` inside a `db.query` span carrying `db.system`, `db.statement`,
+    // `db.rows` and `db.duration_ms`, and logs it when slower than the threshold.
+    // `sql` is only used for the span and the log. Queries run on `pool()` or
+    // `conn()` get the span already, this is for transactions and connections
+    pub async fn traced<T, Fut>(&self, sql: &str, fut: Fut) -> BasicResult<T>
+    where
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+        T: RowCount,
+    {
+        Ok(util_sql::trace::traced::<Postgres, _, _>(self.pool.trace(), sql, fut).await?)
+    }
+}
+
+pub async fn traced<T, Fut>(sql: &str, fut: Fut) -> BasicResult<T>
+where
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+    T: RowCount,
+{
+    default_pool().await?.traced(sql, fut).await
+}
+
+// `traced` for queries on a connection or transaction rather than a pool, with
+// the options of the default pool once initialised
+pub async fn query<T, Fut>(sql: &str, fut: Fut) -> sqlx::Result<T>
+where
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+    T: RowCount,
+{
+    let opts = DEFAULT_POOL
+        .get()
+        .map_or(&*DEFAULT_TRACE, |v| v.pool.trace());
+    util_sql::trace::traced::<Postgres, _, _>(opts, sql, fut).await
+}
+
+pub fn redact(sql: &str) -> String {
+    util_sql::trace::redact::<Postgres>(sql)
+}
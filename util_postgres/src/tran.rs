@@ -0,0 +1,62 @@
+use crate::{default_pool, tenant, SqlPool};
+use sqlx::{Postgres, Transaction};
+use util_error::BasicResult;
+use util_sql::tran::TranPool;
+pub use util_sql::tran::{BoxFuture, IsolationLevel, TranOptions};
+
+pub type TranOn<'a> = util_sql::tran::TranOn<'a, SqlPool, Postgres>;
+
+impl TranPool<Postgres> for SqlPool {
+    fn begin_with<'a>(
+        &'a self,
+        opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<Transaction<'static, Postgres>>> {
+        Box::pin(async move {
+            let mut tx = self.tran().await?;
+            if let Some(sql) = opts.set_transaction_sql() {
+                sqlx::query(&sql).execute(&mut *tx).await?;
+            }
+            if let Some(tenant_id) = &opts.tenant {
+                tenant::set_local(&mut tx, &self.tenant, tenant_id).await?;
+            }
+            Ok(tx)
+        })
+    }
+}
+
+impl SqlPool {
+    // Commits on `Ok`, rolls back on `Err`, and runs `f` again on serialization failures (40001) and deadlocks (40P01)
+    pub async fn with_tran<T, F>(&self, opts: &TranOptions, f: F) -> BasicResult<T>
+    where
+        F: for<'c> FnMut(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, BasicResult<T>>,
+    {
+        util_sql::tran::with_tran(self, opts, f).await
+    }
+}
+
+pub async fn with_tran<T, F>(f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    with_tran_opts(&TranOptions::default(), f).await
+}
+
+pub async fn with_tran_opts<T, F>(opts: &TranOptions, f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, Postgres>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    default_pool().await?.with_tran(opts, f).await
+}
+
+// Nests: on `&mut **tx` of an outer transaction `f` runs in a SAVEPOINT, on a
+// pool in a new transaction, see `TranOn`
+pub async fn with_tran_on<'a, T, F>(
+    on: impl Into<TranOn<'a>>,
+    opts: &TranOptions,
+    f: F,
+) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'a, Postgres>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    util_sql::tran::with_tran_on(on.into(), opts, f).await
+}
@@ -0,0 +1,350 @@
+use crate::{default_client, lock::token, RedisClient};
+use rand::Rng;
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use util_error::{business_error, BasicResult, ErrorKind};
+
+const WAIT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    pub ttl: Duration,
+    // Up to this fraction of `ttl` is added or taken off at random, so keys
+    // written together do not expire together
+    pub jitter: f64,
+    // Caches "not found" for this long, nothing is cached when `None`
+    pub negative_ttl: Option<Duration>,
+    // An expired value is still served for this long, to every caller, while
+    // the one that takes the lock reloads it in the background
+    pub stale_ttl: Option<Duration>,
+    // Upper bound of a load, the single-flight lock expires after it
+    pub lock_ttl: Duration,
+    // How long a miss waits for another caller's load before failing with a
+    // timeout. Nobody loads without the lock, `lock_ttl` frees a stuck one
+    pub wait_timeout: Duration,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(60),
+            jitter: 0.1,
+            negative_ttl: None,
+            stale_ttl: None,
+            lock_ttl: Duration::from_secs(10),
+            wait_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl CacheOptions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    pub fn stale_ttl(mut self, ttl: Duration) -> Self {
+        self.stale_ttl = Some(ttl);
+        self
+    }
+
+    pub fn lock_ttl(mut self, ttl: Duration) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    pub fn wait_timeout(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = timeout;
+        self
+    }
+
+    fn jittered(&self, ttl: Duration) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return ttl;
+        }
+        ttl.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    }
+}
+
+// Stored as JSON, `v` is null for a cached miss
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    #[serde(rename = "v")]
+    value: Option<T>,
+    // unix ms, stale afterwards
+    #[serde(rename = "f")]
+    fresh_until: u64,
+}
+
+fn now_ms() -> BasicResult<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+fn lock_key(key: &str) -> String {
+    format!("{}:lock", key)
+}
+
+impl RedisClient {
+    // Reads `key`, or runs `loader` once across all callers and processes that
+    // miss at the same time and caches what it returns for `ttl`
+    pub async fn get_or_load<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        loader: F,
+    ) -> BasicResult<T>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = BasicResult<T>> + Send + 'static,
+    {
+        let opts = CacheOptions::new(ttl);
+        let loader = || async { loader().await.map(Some) };
+        self.get_or_load_with(key, &opts, loader)
+            .await?
+            .ok_or_else(|| business_error!(format!("cache {} holds a cached miss", key)))
+    }
+
+    // `loader` returns `None` for "not found", cached for `negative_ttl`. It
+    // runs in a task of its own when reloading a stale value
+    pub async fn get_or_load_with<T, F, Fut>(
+        &self,
+        key: &str,
+        opts: &CacheOptions,
+        loader: F,
+    ) -> BasicResult<Option<T>>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = BasicResult<Option<T>>> + Send + 'static,
+    {
+        let stale = match self.read::<T>(key).await? {
+            Some(e) if e.fresh_until > now_ms()? => return Ok(e.value),
+            Some(e) if opts.stale_ttl.is_some() => Some(e.value),
+            _ => None,
+        };
+
+        let lock = lock_key(key);
+        let deadline = Instant::now() + opts.wait_timeout;
+        loop {
+            let token = token();
+            if self.lock_token(&lock, &token, opts.lock_ttl).await? {
+                if let Some(v) = stale {
+                    self.reload(key, opts, token, loader);
+                    return Ok(v);
+                }
+                // filled while we took the lock
+                let res = match self.read::<T>(key).await? {
+                    Some(e) if e.fresh_until > now_ms()? => Ok(e.value),
+                    _ => self.load(key, opts, loader).await,
+                };
+                if let Err(e) = self.unlock_token(&lock, &token).await {
+                    log::error!("cache {} unlock failed, error: {}", key, e);
+                }
+                return res;
+            }
+            if let Some(v) = stale {
+                return Ok(v);
+            }
+
+            // A released lock without a value means the load failed or found
+            // nothing to cache, take the lock ourselves instead of waiting on
+            loop {
+                if Instant::now() >= deadline {
+                    log::warn!("cache {} wait for another load timed out", key);
+                    return Err(ErrorKind::Timeout);
+                }
+                tokio::time::sleep(WAIT_INTERVAL).await;
+                if let Some(e) = self.read::<T>(key).await? {
+                    if e.fresh_until > now_ms()? {
+                        return Ok(e.value);
+                    }
+                }
+                if !self.exists(&lock).await? {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Loads in the background and releases the lock taken as `token`, the
+    // stale value stays until it succeeds
+    fn reload<T, F, Fut>(&self, key: &str, opts: &CacheOptions, token: String, loader: F)
+    where
+        T: Serialize + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = BasicResult<Option<T>>> + Send + 'static,
+    {
+        let (client, key, opts) = (self.clone(), key.to_string(), opts.clone());
+        tokio::spawn(async move {
+            if let Err(e) = client.load(&key, &opts, loader).await {
+                log::warn!("cache {} reload failed, serve stale, error: {}", key, e);
+            }
+            if let Err(e) = client.unlock_token(&lock_key(&key), &token).await {
+                log::error!("cache {} unlock failed, error: {}", key, e);
+            }
+        });
+    }
+
+    async fn read<T>(&self, key: &str) -> BasicResult<Option<Entry<T>>>
+    where
+        T: DeserializeOwned,
+    {
+        let mut conn = self.conn().await?;
+        let raw: Option<Vec<u8>> = self.timed(conn.get(key)).await?;
+        Ok(raw.and_then(|v| match serde_json::from_slice(&v) {
+            Ok(v) => Some(v),
+            // written by an older version of the type, reload it
+            Err(e) => {
+                log::warn!("cache {} decode failed, error: {}", key, e);
+                None
+            }
+        }))
+    }
+
+    async fn load<T, F, Fut>(
+        &self,
+        key: &str,
+        opts: &CacheOptions,
+        loader: F,
+    ) -> BasicResult<Option<T>>
+    where
+        T: Serialize,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = BasicResult<Option<T>>>,
+    {
+        let value = loader().await?;
+        let ttl = match value {
+            Some(_) => Some(opts.ttl),
+            None => opts.negative_ttl,
+        };
+        if let Some(ttl) = ttl {
+            let ttl = opts.jittered(ttl);
+            let raw = serde_json::to_vec(&Entry {
+                value: value.as_ref(),
+                fresh_until: now_ms()? + ttl.as_millis() as u64,
+            })
+            .map_err(|e| business_error!(format!("cache {} encode failed, error: {}", key, e)))?;
+            let expire = ttl + opts.stale_ttl.unwrap_or_default();
+            let mut conn = self.conn().await?;
+            self.timed(
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(raw)
+                    .arg("PX")
+                    .arg(expire.as_millis() as u64)
+                    .query_async::<_, ()>(&mut conn),
+            )
+            .await?;
+        }
+        Ok(value)
+    }
+}
+
+pub async fn get_or_load<T, F, Fut>(key: &str, ttl: Duration, loader: F) -> BasicResult<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = BasicResult<T>> + Send + 'static,
+{
+    default_client()?.get_or_load(key, ttl, loader).await
+}
+
+pub async fn get_or_load_with<T, F, Fut>(
+    key: &str,
+    opts: &CacheOptions,
+    loader: F,
+) -> BasicResult<Option<T>>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = BasicResult<Option<T>>> + Send + 'static,
+{
+    default_client()?.get_or_load_with(key, opts, loader).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_jittered() {
+        let opts = CacheOptions::new(Duration::from_secs(100));
+        for _ in 0..100 {
+            let ttl = opts.jittered(opts.ttl);
+            assert!(ttl >= Duration::from_secs(90) && ttl <= Duration::from_secs(110));
+        }
+        let opts = opts.jitter(0.0);
+        assert_eq!(opts.jittered(opts.ttl), opts.ttl);
+    }
+
+    #[test]
+    fn test_entry() {
+        let raw = serde_json::to_string(&Entry {
+            value: None::<&i32>,
+            fresh_until: 1,
+        })
+        .unwrap();
+        assert_eq!(raw, r#"{"v":null,"f":1}"#);
+        let entry: Entry<i32> = serde_json::from_str(r#"{"v":3,"f":2}"#).unwrap();
+        assert_eq!((entry.value, entry.fresh_until), (Some(3), 2));
+    }
+
+    // Needs a server, skipped unless `TEST_REDIS_URL` is set
+    #[tokio::test]
+    async fn test_waiters_after_failed_load() {
+        let Ok(url) = std::env::var("TEST_REDIS_URL") else {
+            return;
+        };
+        let client = RedisClient::new(Config::from_url(url)).unwrap();
+        let opts = CacheOptions::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+
+        let key = format!("test:cache:{}", token());
+        let res = futures::future::join_all((0..4).map(|_| {
+            let calls = calls.clone();
+            client.get_or_load_with::<i32, _, _>(&key, &opts, move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Err(business_error!("source down"))
+            })
+        }))
+        .await;
+        assert!(res.iter().all(|v| v.is_err()));
+
+        let key = format!("test:cache:{}", token());
+        let res = futures::future::join_all((0..4).map(|_| {
+            let calls = calls.clone();
+            client.get_or_load_with::<i32, _, _>(&key, &opts, move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(None)
+            })
+        }))
+        .await;
+        assert!(res.iter().all(|v| matches!(v, Ok(None))));
+
+        // every caller loaded once the lock was gone, none sat out the timeout
+        assert_eq!(calls.load(Ordering::SeqCst), 8);
+        assert!(start.elapsed() < opts.wait_timeout);
+    }
+}
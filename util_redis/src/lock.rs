@@ -0,0 +1,427 @@
+use crate::{default_client, hash_tag, RedisClient};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use redis::Script;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use util_error::{business_error, BasicResult, ErrorKind};
+
+static ACQUIRE: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then return redis.call("INCR", KEYS[2]) end return 0"#,
+    )
+});
+
+static EXTEND: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("PEXPIRE", KEYS[1], ARGV[2]) end return 0"#,
+    )
+});
+
+static RELEASE: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"if redis.call("GET", KEYS[1]) == ARGV[1] then return redis.call("DEL", KEYS[1]) end return 0"#,
+    )
+});
+
+#[derive(Clone, Debug)]
+pub struct LockOptions {
+    // Lease, the lock frees itself after it unless extended
+    pub ttl: Duration,
+    // `lock` gives up with a timeout error after it
+    pub wait_timeout: Duration,
+    pub retry_interval: Duration,
+    // Extends the lease every third of `ttl` until the guard is released
+    pub auto_extend: bool,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(30),
+            wait_timeout: Duration::from_secs(10),
+            retry_interval: Duration::from_millis(100),
+            auto_extend: true,
+        }
+    }
+}
+
+impl LockOptions {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    pub fn wait_timeout(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = timeout;
+        self
+    }
+
+    pub fn retry_interval(mut self, interval: Duration) -> Self {
+        self.retry_interval = interval;
+        self
+    }
+
+    pub fn auto_extend(mut self, auto_extend: bool) -> Self {
+        self.auto_extend = auto_extend;
+        self
+    }
+}
+
+// PX takes whole milliseconds and the extender runs every third of the lease
+const MIN_TTL: Duration = Duration::from_millis(3);
+
+pub(crate) fn token() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+// Both keys share a hash tag, the acquire script touches them together
+fn keys(name: &str) -> (String, String) {
+    (hash_tag(name, "lock"), hash_tag(name, "fence"))
+}
+
+impl RedisClient {
+    // SET NX PX, true when `key` was free
+    pub(crate) async fn lock_token(
+        &self,
+        key: &str,
+        token: &str,
+        ttl: Duration,
+    ) -> BasicResult<bool> {
+        let mut conn = self.conn().await?;
+        let res: Option<String> = self
+            .timed(
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl.as_millis() as u64)
+                    .query_async(&mut conn),
+            )
+            .await?;
+        Ok(res.is_some())
+    }
+
+    // Deletes `key` only while it still holds `token`
+    pub(crate) async fn unlock_token(&self, key: &str, token: &str) -> BasicResult<bool> {
+        let mut conn = self.conn().await?;
+        let res: i64 = self
+            .timed(RELEASE.key(key).arg(token).invoke_async(&mut conn))
+            .await?;
+        Ok(res == 1)
+    }
+
+    async fn extend_token(&self, key: &str, token: &str, ttl: Duration) -> BasicResult<bool> {
+        let mut conn = self.conn().await?;
+        let res: i64 = self
+            .timed(
+                EXTEND
+                    .key(key)
+                    .arg(token)
+                    .arg(ttl.as_millis() as u64)
+                    .invoke_async(&mut conn),
+            )
+            .await?;
+        Ok(res == 1)
+    }
+
+    // The fencing token when acquired
+    async fn acquire_fenced(
+        &self,
+        name: &str,
+        token: &str,
+        ttl: Duration,
+    ) -> BasicResult<Option<u64>> {
+        let (key, fence) = keys(name);
+        let mut conn = self.conn().await?;
+        let res: u64 = self
+            .timed(
+                ACQUIRE
+                    .key(key)
+                    .key(fence)
+                    .arg(token)
+                    .arg(ttl.as_millis() as u64)
+                    .invoke_async(&mut conn),
+            )
+            .await?;
+        Ok(match res {
+            0 => None,
+            v => Some(v),
+        })
+    }
+
+    pub async fn lock(&self, name: &str, opts: &LockOptions) -> BasicResult<LockGuard> {
+        Redlock::new(vec![self.clone()]).lock(name, opts).await
+    }
+
+    pub async fn try_lock(&self, name: &str, opts: &LockOptions) -> BasicResult<Option<LockGuard>> {
+        Redlock::new(vec![self.clone()]).try_lock(name, opts).await
+    }
+
+    // Runs `f` with the fencing token while holding the lock
+    pub async fn with_lock<T, F, Fut>(&self, name: &str, opts: &LockOptions, f: F) -> BasicResult<T>
+    where
+        F: FnOnce(u64) -> Fut,
+        Fut: Future<Output = BasicResult<T>>,
+    {
+        Redlock::new(vec![self.clone()])
+            .with_lock(name, opts, f)
+            .await
+    }
+}
+
+// A lock held on a majority of independent servers, see the Redlock algorithm.
+// With a single client it is a plain lock on that server. Fencing tokens only
+// increase strictly per server, the guard reports the highest one it got
+#[derive(Clone)]
+pub struct Redlock {
+    clients: Vec<RedisClient>,
+}
+
+impl Redlock {
+    pub fn new(clients: Vec<RedisClient>) -> Self {
+        Self { clients }
+    }
+
+    fn quorum(&self) -> usize {
+        self.clients.len() / 2 + 1
+    }
+
+    pub async fn lock(&self, name: &str, opts: &LockOptions) -> BasicResult<LockGuard> {
+        let deadline = Instant::now() + opts.wait_timeout;
+        loop {
+            if let Some(guard) = self.try_lock(name, opts).await? {
+                return Ok(guard);
+            }
+            if Instant::now() >= deadline {
+                log::warn!("redis lock {} wait timed out", name);
+                return Err(ErrorKind::Timeout);
+            }
+            // random delay, so competitors do not retry in lockstep
+            let half = opts.retry_interval / 2;
+            let delay = half + half.mul_f64(rand::thread_rng().gen::<f64>() * 2.0);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub async fn try_lock(&self, name: &str, opts: &LockOptions) -> BasicResult<Option<LockGuard>> {
+        if opts.ttl < MIN_TTL {
+            return Err(business_error!(format!(
+                "redis lock {} ttl must be at least {:?}",
+                name, MIN_TTL
+            )));
+        }
+        let token = token();
+        let start = Instant::now();
+        let mut fence = 0;
+        let mut acquired = 0;
+        let mut last_err = None;
+        for client in self.clients.iter() {
+            match client.acquire_fenced(name, &token, opts.ttl).await {
+                Ok(Some(v)) => {
+                    acquired += 1;
+                    fence = fence.max(v);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!("redis lock {} acquire failed, error: {}", name, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        // clock drift between servers, as suggested by the Redlock algorithm
+        let drift = opts.ttl / 100 + Duration::from_millis(2);
+        let valid = start.elapsed() + drift < opts.ttl;
+        let mut guard = LockGuard {
+            clients: self.clients.clone(),
+            key: keys(name).0,
+            token,
+            fence,
+            quorum: self.quorum(),
+            lost: Default::default(),
+            extender: None,
+            released: false,
+        };
+        if acquired >= self.quorum() && valid {
+            if opts.auto_extend {
+                guard.extender = Some(guard.extend_in_background(opts.ttl));
+            }
+            return Ok(Some(guard));
+        }
+        guard.release_all().await;
+        guard.released = true;
+        match (acquired, last_err) {
+            (0, Some(e)) if self.clients.len() == 1 => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn with_lock<T, F, Fut>(&self, name: &str, opts: &LockOptions, f: F) -> BasicResult<T>
+    where
+        F: FnOnce(u64) -> Fut,
+        Fut: Future<Output = BasicResult<T>>,
+    {
+        let guard = self.lock(name, opts).await?;
+        let res = f(guard.fence()).await;
+        if guard.lost() {
+            log::error!("redis lock {} lost before the work finished", name);
+        }
+        guard.release().await?;
+        res
+    }
+}
+
+// Released on drop, in the background when a runtime is around. Check `lost`
+// before committing work, the lease may have run out underneath
+pub struct LockGuard {
+    clients: Vec<RedisClient>,
+    key: String,
+    token: String,
+    fence: u64,
+    quorum: usize,
+    lost: Arc<AtomicBool>,
+    extender: Option<oneshot::Sender<()>>,
+    released: bool,
+}
+
+impl LockGuard {
+    // Increases with every acquisition, storage can reject writes carrying an
+    // older token than one already seen
+    pub fn fence(&self) -> u64 {
+        self.fence
+    }
+
+    pub fn lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    pub async fn extend(&self, ttl: Duration) -> BasicResult<bool> {
+        let res = extend(&self.clients, &self.key, &self.token, ttl).await >= self.quorum;
+        if !res {
+            self.lost.store(true, Ordering::Relaxed);
+        }
+        Ok(res)
+    }
+
+    pub async fn release(mut self) -> BasicResult<()> {
+        self.extender.take();
+        self.released = true;
+        let mut last_err = None;
+        for client in self.clients.iter() {
+            if let Err(e) = client.unlock_token(&self.key, &self.token).await {
+                last_err = Some(e);
+            }
+        }
+        match last_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn release_all(&self) {
+        for client in self.clients.iter() {
+            if let Err(e) = client.unlock_token(&self.key, &self.token).await {
+                log::error!("redis unlock {} failed, error: {}", self.key, e);
+            }
+        }
+    }
+
+    fn extend_in_background(&self, ttl: Duration) -> oneshot::Sender<()> {
+        let (sender, mut receiver) = oneshot::channel::<()>();
+        let clients = self.clients.clone();
+        let key = self.key.clone();
+        let token = self.token.clone();
+        let quorum = self.quorum;
+        let lost = self.lost.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ttl / 3);
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if extend(&clients, &key, &token, ttl).await < quorum {
+                            log::error!("redis lock {} lost, extend failed", key);
+                            lost.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                    }
+                    _ = &mut receiver => break,
+                }
+            }
+        });
+        sender
+    }
+}
+
+async fn extend(clients: &[RedisClient], key: &str, token: &str, ttl: Duration) -> usize {
+    let mut res = 0;
+    for client in clients.iter() {
+        match client.extend_token(key, token, ttl).await {
+            Ok(true) => res += 1,
+            Ok(false) => {}
+            Err(e) => log::warn!("redis lock {} extend failed, error: {}", key, e),
+        }
+    }
+    res
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        self.extender.take();
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                let guard = LockGuard {
+                    clients: std::mem::take(&mut self.clients),
+                    key: std::mem::take(&mut self.key),
+                    token: std::mem::take(&mut self.token),
+                    fence: self.fence,
+                    quorum: self.quorum,
+                    lost: self.lost.clone(),
+                    extender: None,
+                    released: true,
+                };
+                handle.spawn(async move { guard.release_all().await });
+            }
+            // expires with its lease
+            Err(_) => log::warn!("redis lock {} dropped outside a runtime", self.key),
+        }
+    }
+}
+
+pub async fn lock(name: &str, opts: &LockOptions) -> BasicResult<LockGuard> {
+    default_client()?.lock(name, opts).await
+}
+
+pub async fn try_lock(name: &str, opts: &LockOptions) -> BasicResult<Option<LockGuard>> {
+    default_client()?.try_lock(name, opts).await
+}
+
+pub async fn with_lock<T, F, Fut>(name: &str, opts: &LockOptions, f: F) -> BasicResult<T>
+where
+    F: FnOnce(u64) -> Fut,
+    Fut: Future<Output = BasicResult<T>>,
+{
+    default_client()?.with_lock(name, opts, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn test_ttl_too_short() {
+        let client = RedisClient::new(Config::default()).unwrap();
+        let opts = LockOptions::new(Duration::from_nanos(2));
+        assert!(client.try_lock("job", &opts).await.is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use crate::{default_client, HandlerOptions, RedisClient};
+use redis::{FromRedisValue, Msg};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc::{self, Sender, UnboundedSender};
+use tokio::sync::oneshot::{self, Receiver};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use util_config::RetryPolicy;
+use util_dispatch::Dispatcher;
+use util_error::{business_error, BasicResult};
+
+#[derive(Clone, Debug, Default)]
+pub struct SubscribeOptions {
+    pub channels: BTreeSet<String>,
+    // PSUBSCRIBE globs, e.g. `orders.*`
+    pub patterns: BTreeSet<String>,
+    // Delay between reconnect attempts, `max_attempts` is ignored, a lost
+    // subscription is retried until closed
+    pub backoff: RetryPolicy,
+}
+
+impl SubscribeOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn channel(mut self, channel: impl Into<String>) -> Self {
+        self.channels.insert(channel.into());
+        self
+    }
+
+    pub fn pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.patterns.insert(pattern.into());
+        self
+    }
+
+    pub fn backoff(mut self, backoff: RetryPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PubSubMessage<T> {
+    pub channel: String,
+    // The pattern that matched, for pattern subscriptions
+    pub pattern: Option<String>,
+    pub payload: T,
+}
+
+enum Change {
+    Subscribe(String),
+    Unsubscribe(String),
+    PSubscribe(String),
+    PUnsubscribe(String),
+}
+
+// Handle of a running subscription. Channels and patterns added here are
+// subscribed on an extra connection and removed ones stop being delivered, the
+// others keep their connection and miss nothing. A reconnect merges them back
+// into one connection, messages published while disconnected are missed
+pub struct Subscription {
+    changes: UnboundedSender<Change>,
+    close_sender: Sender<()>,
+    close_done_receiver: Receiver<()>,
+}
+
+impl Subscription {
+    pub fn subscribe(&self, channel: impl Into<String>) -> BasicResult<()> {
+        self.change(Change::Subscribe(channel.into()))
+    }
+
+    pub fn unsubscribe(&self, channel: impl Into<String>) -> BasicResult<()> {
+        self.change(Change::Unsubscribe(channel.into()))
+    }
+
+    pub fn psubscribe(&self, pattern: impl Into<String>) -> BasicResult<()> {
+        self.change(Change::PSubscribe(pattern.into()))
+    }
+
+    pub fn punsubscribe(&self, pattern: impl Into<String>) -> BasicResult<()> {
+        self.change(Change::PUnsubscribe(pattern.into()))
+    }
+
+    fn change(&self, change: Change) -> BasicResult<()> {
+        self.changes
+            .send(change)
+            .map_err(|_| business_error!("redis subscription already closed"))
+    }
+
+    // Stops the loop and waits for it to end
+    pub async fn close(self) -> BasicResult<()> {
+        let (close_sender, close_done_receiver) = self.into_parts();
+        close_sender
+            .send(())
+            .await
+            .map_err(|_| business_error!("redis subscription already closed"))?;
+        close_done_receiver
+            .await
+            .map_err(|_| business_error!("redis subscription ended abnormally"))
+    }
+
+    // The close channel pair `subscribe` hands out, channels can not be changed
+    // afterwards
+    pub fn into_parts(self) -> (Sender<()>, Receiver<()>) {
+        (self.close_sender, self.close_done_receiver)
+    }
+}
+
+impl SubscribeOptions {
+    fn wants(&self, msg: &Msg) -> bool {
+        match msg.from_pattern() {
+            true => msg
+                .get_pattern::<String>()
+                .is_ok_and(|v| self.patterns.contains(&v)),
+            false => self.channels.contains(msg.get_channel_name()),
+        }
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Subscribe(v) => self.channels.insert(v),
+            Change::Unsubscribe(v) => self.channels.remove(&v),
+            Change::PSubscribe(v) => self.patterns.insert(v),
+            Change::PUnsubscribe(v) => self.patterns.remove(&v),
+        };
+    }
+}
+
+// The connections of one subscription and what each subscribed. Channels added
+// later get a connection of their own, so the ones already subscribed are never
+// interrupted
+#[derive(Default)]
+struct Connections {
+    next: u64,
+    subscribed: HashMap<u64, SubscribeOptions>,
+    // yields `None` once the connection is gone
+    streams: StreamMap<u64, Pin<Box<dyn Stream<Item = Option<Msg>> + Send>>>,
+}
+
+impl Connections {
+    fn add(&mut self, opts: SubscribeOptions, stream: impl Stream<Item = Msg> + Send + 'static) {
+        let stream = stream.map(Some).chain(tokio_stream::once(None));
+        self.streams.insert(self.next, Box::pin(stream));
+        self.subscribed.insert(self.next, opts);
+        self.next += 1;
+    }
+
+    // Closes the connections with nothing left in `opts`. A removed channel sharing
+    // a connection with a kept one is only filtered out
+    fn retain(&mut self, opts: &SubscribeOptions) {
+        let streams = &mut self.streams;
+        self.subscribed.retain(|id, v| {
+            let keep =
+                !v.channels.is_disjoint(&opts.channels) || !v.patterns.is_disjoint(&opts.patterns);
+            if !keep {
+                streams.remove(id);
+            }
+            keep
+        });
+    }
+
+    // What `opts` has that no connection subscribed
+    fn missing(&self, opts: &SubscribeOptions) -> SubscribeOptions {
+        let mut res = SubscribeOptions::new();
+        res.channels = opts.channels.clone();
+        res.patterns = opts.patterns.clone();
+        for v in self.subscribed.values() {
+            res.channels.retain(|c| !v.channels.contains(c));
+            res.patterns.retain(|p| !v.patterns.contains(p));
+        }
+        res
+    }
+}
+
+impl RedisClient {
+    async fn open_subscription(
+        &self,
+        opts: &SubscribeOptions,
+    ) -> BasicResult<impl Stream<Item = Msg>> {
+        let mut pubsub = self.dedicated().await?.into_pubsub();
+        if !opts.channels.is_empty() {
+            pubsub
+                .subscribe(opts.channels.iter().collect::<Vec<_>>())
+                .await?;
+        }
+        if !opts.patterns.is_empty() {
+            pubsub
+                .psubscribe(opts.patterns.iter().collect::<Vec<_>>())
+                .await?;
+        }
+        Ok(pubsub.into_on_message())
+    }
+
+    // Subscribes to every channel and pattern in `opts` on a connection of its
+    // own. A dropped connection is reopened with backoff and subscribed again
+    pub async fn subscribe_with<F>(
+        &self,
+        opts: SubscribeOptions,
+        mut f: F,
+    ) -> BasicResult<Subscription>
+    where
+        F: FnMut(Msg) + Send + 'static,
+    {
+        let handler = move |msg| {
+            f(msg);
+            futures::future::ready(Ok(()))
+        };
+        self.subscribe_async(opts, HandlerOptions::default(), handler)
+            .await
+    }
+
+    // `f` may await, e.g. a database write. Running handlers are let finish
+    // before a resubscribe and before the loop ends on close
+    pub async fn subscribe_async<F, Fut>(
+        &self,
+        mut opts: SubscribeOptions,
+        handler: HandlerOptions,
+        f: F,
+    ) -> BasicResult<Subscription>
+    where
+        F: FnMut(Msg) -> Fut + Send + 'static,
+        Fut: Future<Output = BasicResult<()>> + Send + 'static,
+    {
+        let first = self.open_subscription(&opts).await?;
+        let client = self.clone();
+        let (changes, mut change_receiver) = mpsc::unbounded_channel::<Change>();
+        let (close_sender, mut close_receiver) = mpsc::channel::<()>(1);
+        let (close_done_sender, close_done_receiver) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let mut dispatcher =
+                Dispatcher::new(handler, |msg: &Msg| msg.get_channel_name().to_string(), f);
+            let mut conns = Connections::default();
+            conns.add(opts.clone(), first);
+            let mut lost = false;
+            let mut retry = 0;
+            'l: loop {
+                if lost {
+                    dispatcher.drain().await;
+                    if retry > 0 {
+                        tokio::select! {
+                            _ = tokio::time::sleep(opts.backoff.backoff(retry)) => {}
+                            Some(_) = close_receiver.recv() => break 'l,
+                        }
+                    }
+                    match client.open_subscription(&opts).await {
+                        Ok(v) => {
+                            conns = Connections::default();
+                            conns.add(opts.clone(), v);
+                            lost = false;
+                        }
+                        Err(e) => {
+                            log::error!("redis resubscribe failed, error: {}", e);
+                            retry += 1;
+                            continue;
+                        }
+                    }
+                }
+                tokio::select! {
+                    Some((_, v)) = conns.streams.next(), if dispatcher.has_room() && !conns.streams.is_empty() => match v {
+                        Some(v) => {
+                            if opts.wants(&v) {
+                                dispatcher.push(v);
+                            }
+                        }
+                        None => {
+                            log::warn!("redis subscription lost, resubscribe");
+                            retry = 1;
+                            lost = true;
+                        }
+                    },
+                    _ = dispatcher.next_done(), if !dispatcher.is_idle() => {}
+                    Some(change) = change_receiver.recv() => {
+                        opts.apply(change);
+                        while let Ok(change) = change_receiver.try_recv() {
+                            opts.apply(change);
+                        }
+                        conns.retain(&opts);
+                        let added = conns.missing(&opts);
+                        if added.channels.is_empty() && added.patterns.is_empty() {
+                            continue;
+                        }
+                        match client.open_subscription(&added).await {
+                            Ok(v) => conns.add(added, v),
+                            Err(e) => {
+                                log::error!("redis subscribe failed, resubscribe, error: {}", e);
+                                retry = 1;
+                                lost = true;
+                            }
+                        }
+                    }
+                    Some(_) = close_receiver.recv() => break 'l,
+                }
+            }
+            dispatcher.drain().await;
+            let _ = close_done_sender.send(());
+        });
+        Ok(Subscription {
+            changes,
+            close_sender,
+            close_done_receiver,
+        })
+    }
+
+    // Payloads are decoded as `T`, e.g. a `#[from_redis]` struct. Messages that
+    // fail to decode are logged and skipped
+    pub async fn subscribe_typed<T, F>(
+        &self,
+        opts: SubscribeOptions,
+        mut f: F,
+    ) -> BasicResult<Subscription>
+    where
+        T: FromRedisValue,
+        F: FnMut(PubSubMessage<T>) + Send + 'static,
+    {
+        self.subscribe_with(opts, move |msg| match msg.get_payload::<T>() {
+            Ok(payload) => f(PubSubMessage {
+                channel: msg.get_channel_name().to_string(),
+                pattern: msg.get_pattern().ok(),
+                payload,
+            }),
+            Err(e) => log::error!(
+                "redis message on {} decode failed, error: {}",
+                msg.get_channel_name(),
+                e
+            ),
+        })
+        .await
+    }
+}
+
+pub async fn subscribe_with<F>(opts: SubscribeOptions, f: F) -> BasicResult<Subscription>
+where
+    F: FnMut(Msg) + Send + 'static,
+{
+    default_client()?.subscribe_with(opts, f).await
+}
+
+pub async fn subscribe_async<F, Fut>(
+    opts: SubscribeOptions,
+    handler: HandlerOptions,
+    f: F,
+) -> BasicResult<Subscription>
+where
+    F: FnMut(Msg) -> Fut + Send + 'static,
+    Fut: Future<Output = BasicResult<()>> + Send + 'static,
+{
+    default_client()?.subscribe_async(opts, handler, f).await
+}
+
+pub async fn subscribe_typed<T, F>(opts: SubscribeOptions, f: F) -> BasicResult<Subscription>
+where
+    T: FromRedisValue,
+    F: FnMut(PubSubMessage<T>) + Send + 'static,
+{
+    default_client()?.subscribe_typed(opts, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_changes() {
+        let mut opts = SubscribeOptions::new().channel("a").pattern("b.*");
+        opts.apply(Change::Subscribe("c".into()));
+        opts.apply(Change::Unsubscribe("a".into()));
+        opts.apply(Change::PUnsubscribe("b.*".into()));
+        assert_eq!(opts.channels.into_iter().collect::<Vec<_>>(), vec!["c"]);
+        assert!(opts.patterns.is_empty());
+    }
+
+    #[test]
+    fn test_changes_keep_connections() {
+        let mut conns = Connections::default();
+        let first = SubscribeOptions::new().channel("a").channel("b");
+        conns.add(first.clone(), tokio_stream::pending());
+
+        // a new channel needs a connection, the existing one stays
+        let mut opts = first.channel("c");
+        let added = conns.missing(&opts);
+        assert_eq!(added.channels.iter().collect::<Vec<_>>(), vec!["c"]);
+        conns.add(added, tokio_stream::pending());
+
+        // `b` shares the first connection with `a`, it is only filtered out
+        opts.apply(Change::Unsubscribe("b".into()));
+        conns.retain(&opts);
+        assert_eq!(conns.streams.len(), 2);
+        assert!(conns.missing(&opts).channels.is_empty());
+
+        opts.apply(Change::Unsubscribe("c".into()));
+        conns.retain(&opts);
+        assert_eq!(conns.streams.len(), 1);
+    }
+}
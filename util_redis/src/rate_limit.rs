@@ -0,0 +1,290 @@
+use crate::{default_client, lock::token, RedisClient};
+use once_cell::sync::Lazy;
+use redis::Script;
+use serde::Deserialize;
+use std::time::Duration;
+use util_error::BasicResult;
+
+// Server time keeps replicas with drifting clocks on one window
+static SLIDING_WINDOW: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+local t = redis.call("TIME")
+local now = t[1] * 1000 + math.floor(t[2] / 1000)
+local window = tonumber(ARGV[1])
+local limit = tonumber(ARGV[2])
+redis.call("ZREMRANGEBYSCORE", KEYS[1], "-inf", now - window)
+local count = redis.call("ZCARD", KEYS[1])
+local allowed = 0
+if count < limit then
+    redis.call("ZADD", KEYS[1], now, now .. "-" .. ARGV[3])
+    redis.call("PEXPIRE", KEYS[1], window)
+    count = count + 1
+    allowed = 1
+end
+local reset = 0
+local oldest = redis.call("ZRANGE", KEYS[1], 0, 0, "WITHSCORES")
+if oldest[2] then
+    reset = tonumber(oldest[2]) + window - now
+end
+local retry = 0
+if allowed == 0 then
+    retry = reset
+end
+return {allowed, limit - count, retry, reset}
+"#,
+    )
+});
+
+static TOKEN_BUCKET: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r#"
+local t = redis.call("TIME")
+local now = t[1] * 1000 + math.floor(t[2] / 1000)
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local bucket = redis.call("HMGET", KEYS[1], "tokens", "ts")
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+local allowed = 0
+if tokens >= cost then
+    tokens = tokens - cost
+    allowed = 1
+end
+redis.call("HSET", KEYS[1], "tokens", tostring(tokens), "ts", now)
+redis.call("PEXPIRE", KEYS[1], math.ceil(capacity / rate))
+local retry = 0
+if allowed == 0 then
+    retry = math.ceil((cost - tokens) / rate)
+end
+return {allowed, math.floor(tokens), retry, math.ceil((capacity - tokens) / rate)}
+"#,
+    )
+});
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Limiter {
+    // At most `limit` requests in any `window_ms`, exact, one sorted set entry
+    // per request
+    SlidingWindow { limit: u64, window_ms: u64 },
+    // Bursts up to `capacity`, refilled at `refill_per_sec`
+    TokenBucket { capacity: u64, refill_per_sec: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // Zero when allowed
+    pub retry_after: Duration,
+    // Until the quota is full again
+    pub reset_after: Duration,
+}
+
+impl Limiter {
+    fn limit(&self) -> u64 {
+        match self {
+            Limiter::SlidingWindow { limit, .. } => *limit,
+            Limiter::TokenBucket { capacity, .. } => *capacity,
+        }
+    }
+}
+
+impl RedisClient {
+    // Takes one request off the quota of `key`, e.g. `rate:login:{ip}`
+    pub async fn rate_limit(&self, key: &str, limiter: &Limiter) -> BasicResult<RateLimit> {
+        let mut conn = self.conn().await?;
+        let (allowed, remaining, retry, reset): (i64, i64, u64, u64) = match limiter {
+            Limiter::SlidingWindow { limit, window_ms } => {
+                self.timed(
+                    SLIDING_WINDOW
+                        .key(key)
+                        .arg(window_ms)
+                        .arg(limit)
+                        .arg(token())
+                        .invoke_async(&mut conn),
+                )
+                .await?
+            }
+            Limiter::TokenBucket {
+                capacity,
+                refill_per_sec,
+            } => {
+                self.timed(
+                    TOKEN_BUCKET
+                        .key(key)
+                        .arg(capacity)
+                        .arg(refill_per_sec / 1000.0)
+                        .arg(1)
+                        .invoke_async(&mut conn),
+                )
+                .await?
+            }
+        };
+        Ok(RateLimit {
+            allowed: allowed == 1,
+            limit: limiter.limit(),
+            remaining: remaining.max(0) as u64,
+            retry_after: Duration::from_millis(retry),
+            reset_after: Duration::from_millis(reset),
+        })
+    }
+}
+
+pub async fn rate_limit(key: &str, limiter: &Limiter) -> BasicResult<RateLimit> {
+    default_client()?.rate_limit(key, limiter).await
+}
+
+#[cfg(feature = "actix-web")]
+pub use self::actix::RateLimiter;
+
+#[cfg(feature = "actix-web")]
+mod actix {
+    use super::{Limiter, RateLimit};
+    use crate::default_client;
+    use actix_web::body::{BoxBody, MessageBody};
+    use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use futures::future::{ready, LocalBoxFuture, Ready};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use util_error::too_many_requests;
+
+    type KeyFn = Arc<dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync>;
+
+    // Replies 429 with `Retry-After` once a key runs out of quota, and sets the
+    // `x-ratelimit-*` headers otherwise. Requests pass when redis is down
+    #[derive(Clone)]
+    pub struct RateLimiter {
+        limiter: Limiter,
+        prefix: String,
+        key: KeyFn,
+    }
+
+    impl RateLimiter {
+        // `key` picks who is limited, `None` lets the request through unlimited
+        pub fn new<F>(prefix: impl Into<String>, limiter: Limiter, key: F) -> Self
+        where
+            F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+        {
+            Self {
+                limiter,
+                prefix: prefix.into(),
+                key: Arc::new(key),
+            }
+        }
+
+        // Per client address, honouring `Forwarded` and `X-Forwarded-For`, so only
+        // behind a proxy that sets them
+        pub fn by_ip(prefix: impl Into<String>, limiter: Limiter) -> Self {
+            Self::new(prefix, limiter, |req| {
+                req.connection_info().realip_remote_addr().map(String::from)
+            })
+        }
+    }
+
+    impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+            + 'static,
+        B: MessageBody + 'static,
+    {
+        type Response = ServiceResponse<BoxBody>;
+        type Error = actix_web::Error;
+        type Transform = RateLimiterMiddleware<S>;
+        type InitError = ();
+        type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+        fn new_transform(&self, service: S) -> Self::Future {
+            ready(Ok(RateLimiterMiddleware {
+                service: Rc::new(service),
+                limiter: self.clone(),
+            }))
+        }
+    }
+
+    pub struct RateLimiterMiddleware<S> {
+        service: Rc<S>,
+        limiter: RateLimiter,
+    }
+
+    impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+            + 'static,
+        B: MessageBody + 'static,
+    {
+        type Response = ServiceResponse<BoxBody>;
+        type Error = actix_web::Error;
+        type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        forward_ready!(service);
+
+        fn call(&self, req: ServiceRequest) -> Self::Future {
+            let service = self.service.clone();
+            let limiter = self.limiter.clone();
+            Box::pin(async move {
+                let key = match (limiter.key)(&req) {
+                    Some(v) => format!("{}:{}", limiter.prefix, v),
+                    None => return Ok(service.call(req).await?.map_into_boxed_body()),
+                };
+                let res = match default_client() {
+                    Ok(client) => client.rate_limit(&key, &limiter.limiter).await,
+                    Err(e) => Err(e),
+                };
+                let limit = match res {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("rate limit {} failed, let it pass, error: {}", key, e);
+                        return Ok(service.call(req).await?.map_into_boxed_body());
+                    }
+                };
+                if !limit.allowed {
+                    let retry_after = limit.retry_after.as_millis().div_ceil(1000) as u64;
+                    let e = too_many_requests!(format!("rate limit {} exceeded", key), retry_after);
+                    let mut res = req.error_response(e);
+                    set_headers(res.headers_mut(), &limit);
+                    return Ok(res);
+                }
+                let mut res = service.call(req).await?.map_into_boxed_body();
+                set_headers(res.headers_mut(), &limit);
+                Ok(res)
+            })
+        }
+    }
+
+    fn set_headers(headers: &mut actix_web::http::header::HeaderMap, limit: &RateLimit) {
+        let reset = limit.reset_after.as_millis().div_ceil(1000) as u64;
+        for (name, value) in [
+            ("x-ratelimit-limit", limit.limit),
+            ("x-ratelimit-remaining", limit.remaining),
+            ("x-ratelimit-reset", reset),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_config() {
+        let limiter: Limiter =
+            serde_json::from_str(r#"{"kind":"token_bucket","capacity":20,"refill_per_sec":0.5}"#)
+                .unwrap();
+        assert_eq!(
+            limiter,
+            Limiter::TokenBucket {
+                capacity: 20,
+                refill_per_sec: 0.5
+            }
+        );
+        assert_eq!(limiter.limit(), 20);
+    }
+}
@@ -0,0 +1,430 @@
+use crate::{default_client, hash_tag, RedisClient};
+use redis::aio::Connection;
+use redis::streams::{StreamId, StreamPendingCountReply, StreamRangeReply, StreamReadReply};
+use redis::{FromRedisValue, Value};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::oneshot::{self, Receiver};
+use util_error::{business_error, BasicResult};
+
+const PAYLOAD: &str = "payload";
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct ConsumerOptions {
+    pub stream: String,
+    pub group: String,
+    // Unique per running process, entries pending on it are what gets reclaimed
+    pub consumer: String,
+    // Entries read at a time, the next batch is only read once the callback has
+    // handled this one
+    pub batch_size: usize,
+    pub block: Duration,
+    // Where a new group starts, `$` for new entries only, `0` for the whole stream
+    pub start_id: String,
+    // Entries a consumer left unacknowledged for this long are taken over
+    pub min_idle: Duration,
+    pub claim_interval: Duration,
+    // Moved to `dead_letter` once delivered more often than this
+    pub max_deliveries: usize,
+    // `{stream}:dead` by default, next to the stream in a cluster
+    pub dead_letter: String,
+}
+
+impl ConsumerOptions {
+    pub fn new(
+        stream: impl Into<String>,
+        group: impl Into<String>,
+        consumer: impl Into<String>,
+    ) -> Self {
+        let stream = stream.into();
+        Self {
+            dead_letter: hash_tag(&stream, "dead"),
+            stream,
+            group: group.into(),
+            consumer: consumer.into(),
+            batch_size: 10,
+            block: Duration::from_secs(5),
+            start_id: "$".to_string(),
+            min_idle: Duration::from_secs(60),
+            claim_interval: Duration::from_secs(30),
+            max_deliveries: 5,
+        }
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn start_id(mut self, start_id: impl Into<String>) -> Self {
+        self.start_id = start_id.into();
+        self
+    }
+
+    pub fn min_idle(mut self, min_idle: Duration) -> Self {
+        self.min_idle = min_idle;
+        self
+    }
+
+    pub fn max_deliveries(mut self, max_deliveries: usize) -> Self {
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    pub fn dead_letter(mut self, stream: impl Into<String>) -> Self {
+        self.dead_letter = stream.into();
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamMessage<T> {
+    pub id: String,
+    pub payload: T,
+    // 1 on first delivery
+    pub deliveries: usize,
+}
+
+impl RedisClient {
+    // Appends `payload` as JSON, returns the entry id
+    pub async fn xadd<T>(&self, stream: &str, payload: &T) -> BasicResult<String>
+    where
+        T: Serialize,
+    {
+        self.xadd_maxlen(stream, None, payload).await
+    }
+
+    // Trims the stream to about `maxlen` entries on the way
+    pub async fn xadd_maxlen<T>(
+        &self,
+        stream: &str,
+        maxlen: Option<usize>,
+        payload: &T,
+    ) -> BasicResult<String>
+    where
+        T: Serialize,
+    {
+        let raw = serde_json::to_string(payload)
+            .map_err(|e| business_error!(format!("xadd {} encode failed, error: {}", stream, e)))?;
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(stream);
+        if let Some(maxlen) = maxlen {
+            cmd.arg("MAXLEN").arg("~").arg(maxlen);
+        }
+        cmd.arg("*").arg(PAYLOAD).arg(raw);
+        let mut conn = self.conn().await?;
+        self.timed(cmd.query_async(&mut conn)).await
+    }
+
+    // Reads the group on a connection of its own. `f` returning `Ok` acknowledges
+    // the entry, `Err` leaves it pending to be retried after `min_idle`, by this
+    // or another consumer. Entries that can not be decoded or keep failing go to
+    // the dead letter stream
+    pub async fn consume_stream<T, F>(
+        &self,
+        opts: ConsumerOptions,
+        mut f: F,
+    ) -> BasicResult<(Sender<()>, Receiver<()>)>
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: FnMut(StreamMessage<T>) -> BasicResult<()> + Send + 'static,
+    {
+        self.create_group(&opts).await?;
+        let client = self.clone();
+        let mut conn = Some(client.dedicated_for(&opts.stream).await?);
+        let (close_sender, mut close_receiver) = mpsc::channel::<()>(1);
+        let (close_done_sender, close_done_receiver) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let mut next_claim = Instant::now();
+            'l: loop {
+                tokio::select! {
+                    res = consume_once(&client, &mut conn, &opts, &mut next_claim, &mut f) => {
+                        if let Err(e) = res {
+                            log::error!("redis stream {} consume failed, error: {}", opts.stream, e);
+                            conn = None;
+                            tokio::select! {
+                                _ = tokio::time::sleep(RECONNECT_BACKOFF) => {}
+                                Some(_) = close_receiver.recv() => break 'l,
+                            }
+                        }
+                    }
+                    Some(_) = close_receiver.recv() => {
+                        break 'l
+                    }
+                }
+            }
+            let _ = close_done_sender.send(());
+        });
+        Ok((close_sender, close_done_receiver))
+    }
+
+    async fn create_group(&self, opts: &ConsumerOptions) -> BasicResult<()> {
+        let mut conn = self.conn().await?;
+        let res = self
+            .timed(
+                redis::cmd("XGROUP")
+                    .arg("CREATE")
+                    .arg(&opts.stream)
+                    .arg(&opts.group)
+                    .arg(&opts.start_id)
+                    .arg("MKSTREAM")
+                    .query_async::<_, ()>(&mut conn),
+            )
+            .await;
+        match res {
+            Err(util_error::ErrorKind::Redis(e)) if e.code() == Some("BUSYGROUP") => Ok(()),
+            res => res,
+        }
+    }
+}
+
+async fn consume_once<T, F>(
+    client: &RedisClient,
+    conn: &mut Option<Connection>,
+    opts: &ConsumerOptions,
+    next_claim: &mut Instant,
+    f: &mut F,
+) -> BasicResult<()>
+where
+    T: DeserializeOwned,
+    F: FnMut(StreamMessage<T>) -> BasicResult<()>,
+{
+    if conn.is_none() {
+        *conn = Some(client.dedicated_for(&opts.stream).await?);
+    }
+    let conn = conn.as_mut().unwrap();
+
+    if Instant::now() >= *next_claim {
+        *next_claim = Instant::now() + opts.claim_interval;
+        let mut start = "0-0".to_string();
+        loop {
+            let (next, entries) = autoclaim(conn, opts, &start).await?;
+            if !entries.is_empty() {
+                let deliveries = deliveries(conn, opts, &entries).await?;
+                handle(client, conn, opts, entries, &deliveries, f).await?;
+            }
+            if next == "0-0" {
+                break;
+            }
+            start = next;
+        }
+    }
+
+    let reply: Option<StreamReadReply> = redis::cmd("XREADGROUP")
+        .arg("GROUP")
+        .arg(&opts.group)
+        .arg(&opts.consumer)
+        .arg("COUNT")
+        .arg(opts.batch_size)
+        .arg("BLOCK")
+        .arg(opts.block.as_millis() as u64)
+        .arg("STREAMS")
+        .arg(&opts.stream)
+        .arg(">")
+        .query_async(conn)
+        .await?;
+    for key in reply.map(|v| v.keys).unwrap_or_default() {
+        handle(client, conn, opts, key.ids, &HashMap::new(), f).await?;
+    }
+    Ok(())
+}
+
+// Takes over entries idle longer than `min_idle`, returns the cursor to go on from
+async fn autoclaim(
+    conn: &mut Connection,
+    opts: &ConsumerOptions,
+    start: &str,
+) -> BasicResult<(String, Vec<StreamId>)> {
+    let reply: Value = redis::cmd("XAUTOCLAIM")
+        .arg(&opts.stream)
+        .arg(&opts.group)
+        .arg(&opts.consumer)
+        .arg(opts.min_idle.as_millis() as u64)
+        .arg(start)
+        .arg("COUNT")
+        .arg(opts.batch_size)
+        .query_async(conn)
+        .await?;
+    match reply {
+        Value::Bulk(items) if items.len() >= 2 => {
+            let next = String::from_redis_value(&items[0])?;
+            let entries = StreamRangeReply::from_redis_value(&items[1])?.ids;
+            // entries deleted meanwhile come back without an id on older servers
+            let entries = entries.into_iter().filter(|v| !v.id.is_empty()).collect();
+            Ok((next, entries))
+        }
+        other => Err(business_error!(format!(
+            "unexpected XAUTOCLAIM reply {:?}",
+            other
+        ))),
+    }
+}
+
+async fn deliveries(
+    conn: &mut Connection,
+    opts: &ConsumerOptions,
+    entries: &[StreamId],
+) -> BasicResult<HashMap<String, usize>> {
+    let reply: StreamPendingCountReply = redis::cmd("XPENDING")
+        .arg(&opts.stream)
+        .arg(&opts.group)
+        .arg(&entries[0].id)
+        .arg(&entries[entries.len() - 1].id)
+        .arg(entries.len())
+        .arg(&opts.consumer)
+        .query_async(conn)
+        .await?;
+    Ok(reply
+        .ids
+        .into_iter()
+        .map(|v| (v.id, v.times_delivered))
+        .collect())
+}
+
+async fn handle<T, F>(
+    client: &RedisClient,
+    conn: &mut Connection,
+    opts: &ConsumerOptions,
+    entries: Vec<StreamId>,
+    deliveries: &HashMap<String, usize>,
+    f: &mut F,
+) -> BasicResult<()>
+where
+    T: DeserializeOwned,
+    F: FnMut(StreamMessage<T>) -> BasicResult<()>,
+{
+    for entry in entries {
+        let n = deliveries.get(&entry.id).copied().unwrap_or(1);
+        let raw: Option<String> = entry.get(PAYLOAD);
+        if n > opts.max_deliveries {
+            dead_letter(
+                client,
+                conn,
+                opts,
+                &entry,
+                raw,
+                n,
+                "max deliveries exceeded",
+            )
+            .await?;
+            continue;
+        }
+        let payload = match raw.as_deref().map(serde_json::from_str::<T>) {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => {
+                let reason = format!("decode failed, error: {}", e);
+                dead_letter(client, conn, opts, &entry, raw, n, &reason).await?;
+                continue;
+            }
+            None => {
+                dead_letter(client, conn, opts, &entry, raw, n, "payload missing").await?;
+                continue;
+            }
+        };
+        let id = entry.id;
+        match f(StreamMessage {
+            id: id.clone(),
+            payload,
+            deliveries: n,
+        }) {
+            Ok(()) => {
+                redis::cmd("XACK")
+                    .arg(&opts.stream)
+                    .arg(&opts.group)
+                    .arg(&id)
+                    .query_async::<_, ()>(conn)
+                    .await?;
+            }
+            Err(e) => log::warn!(
+                "redis stream {} entry {} failed, delivery {}, error: {}",
+                opts.stream,
+                id,
+                n,
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+async fn dead_letter(
+    client: &RedisClient,
+    conn: &mut Connection,
+    opts: &ConsumerOptions,
+    entry: &StreamId,
+    raw: Option<String>,
+    deliveries: usize,
+    reason: &str,
+) -> BasicResult<()> {
+    log::error!(
+        "redis stream {} entry {} moved to {}, {}",
+        opts.stream,
+        entry.id,
+        opts.dead_letter,
+        reason
+    );
+    // written through the shared connection, a custom dead letter stream may
+    // live on another cluster node, acknowledged only once it is stored
+    let mut shared = client.conn().await?;
+    client
+        .timed(
+            redis::cmd("XADD")
+                .arg(&opts.dead_letter)
+                .arg("*")
+                .arg(PAYLOAD)
+                .arg(raw.unwrap_or_default())
+                .arg("stream")
+                .arg(&opts.stream)
+                .arg("id")
+                .arg(&entry.id)
+                .arg("deliveries")
+                .arg(deliveries)
+                .arg("reason")
+                .arg(reason)
+                .query_async::<_, ()>(&mut shared),
+        )
+        .await?;
+    redis::cmd("XACK")
+        .arg(&opts.stream)
+        .arg(&opts.group)
+        .arg(&entry.id)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn xadd<T>(stream: &str, payload: &T) -> BasicResult<String>
+where
+    T: Serialize,
+{
+    default_client()?.xadd(stream, payload).await
+}
+
+pub async fn consume_stream<T, F>(
+    opts: ConsumerOptions,
+    f: F,
+) -> BasicResult<(Sender<()>, Receiver<()>)>
+where
+    T: DeserializeOwned + Send + 'static,
+    F: FnMut(StreamMessage<T>) -> BasicResult<()> + Send + 'static,
+{
+    default_client()?.consume_stream(opts, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consumer_options() {
+        let opts = ConsumerOptions::new("orders", "billing", "worker-1").batch_size(0);
+        assert_eq!(opts.dead_letter, "{orders}:dead");
+        assert_eq!(crate::slot(&opts.dead_letter), crate::slot(&opts.stream));
+        assert_eq!(opts.batch_size, 1);
+        let opts = opts.dead_letter("orders:failed");
+        assert_eq!(opts.dead_letter, "orders:failed");
+    }
+}
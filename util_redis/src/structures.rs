@@ -0,0 +1,826 @@
+use crate::{default_client, RedisClient};
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use redis::aio::Connection;
+use redis::{AsyncCommands, FromRedisValue, ToRedisArgs};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroUsize;
+use util_error::{business_error, BasicResult};
+
+// Fields to HSET and fields to HDEL
+type Fields = (Vec<(String, String)>, Vec<String>);
+
+// Hash fields of a struct. Every value is JSON, so strings keep their quotes
+// while numbers stay usable by HINCRBY
+fn encode_fields<T: Serialize>(v: &T) -> BasicResult<Fields> {
+    let serde_json::Value::Object(map) = serde_json::to_value(v)
+        .map_err(|e| business_error!(format!("hash encode failed, error: {}", e)))?
+    else {
+        return Err(business_error!("hash value must serialize to a map"));
+    };
+    let mut set = Vec::with_capacity(map.len());
+    let mut del = Vec::new();
+    for (k, v) in map {
+        match v {
+            serde_json::Value::Null => del.push(k),
+            v => set.push((k, v.to_string())),
+        }
+    }
+    Ok((set, del))
+}
+
+fn decode_fields<T: DeserializeOwned>(fields: HashMap<String, String>) -> BasicResult<T> {
+    let mut map = serde_json::Map::with_capacity(fields.len());
+    for (k, v) in fields {
+        let v = serde_json::from_str(&v)
+            .map_err(|e| business_error!(format!("hash field {} is not json, error: {}", k, e)))?;
+        map.insert(k, v);
+    }
+    serde_json::from_value(serde_json::Value::Object(map))
+        .map_err(|e| business_error!(format!("hash decode failed, error: {}", e)))
+}
+
+// One of the SCAN family, `key` is unset for SCAN itself
+struct Scan {
+    client: RedisClient,
+    command: &'static str,
+    key: Option<String>,
+    pattern: Option<String>,
+    count: usize,
+    // The cluster master to scan, the shared connection otherwise
+    node: Option<Connection>,
+}
+
+impl Scan {
+    // SCAN walks the keys of one node, in a cluster it runs on every master in
+    // turn
+    async fn per_master(self) -> BasicResult<Vec<Scan>> {
+        let Some(nodes) = self.client.dedicated_masters().await? else {
+            return Ok(vec![self]);
+        };
+        Ok(nodes
+            .into_iter()
+            .map(|node| Scan {
+                client: self.client.clone(),
+                command: self.command,
+                key: self.key.clone(),
+                pattern: self.pattern.clone(),
+                count: self.count,
+                node: Some(node),
+            })
+            .collect())
+    }
+
+    fn stream<T>(self) -> impl Stream<Item = BasicResult<T>> + Send + 'static
+    where
+        T: FromRedisValue + Send + 'static,
+    {
+        let state = (self, Some(0u64), VecDeque::new());
+        stream::unfold(state, |(mut scan, mut cursor, mut buf)| async move {
+            loop {
+                if let Some(v) = buf.pop_front() {
+                    return Some((Ok(v), (scan, cursor, buf)));
+                }
+                let current = cursor?;
+                match scan.page::<T>(current).await {
+                    Ok((next, items)) => {
+                        buf.extend(items);
+                        cursor = (next != 0).then_some(next);
+                    }
+                    Err(e) => return Some((Err(e), (scan, None, buf))),
+                }
+            }
+        })
+    }
+
+    async fn page<T: FromRedisValue>(&mut self, cursor: u64) -> BasicResult<(u64, Vec<T>)> {
+        let mut cmd = redis::cmd(self.command);
+        if let Some(key) = &self.key {
+            cmd.arg(key);
+        }
+        cmd.arg(cursor);
+        if let Some(pattern) = &self.pattern {
+            cmd.arg("MATCH").arg(pattern);
+        }
+        cmd.arg("COUNT").arg(self.count);
+        match &mut self.node {
+            Some(node) => self.client.timed(cmd.query_async(node)).await,
+            None => {
+                let mut conn = self.client.conn().await?;
+                self.client.timed(cmd.query_async(&mut conn)).await
+            }
+        }
+    }
+}
+
+const SCAN_COUNT: usize = 100;
+
+impl RedisClient {
+    // True when `k` exists and got the timeout
+    pub async fn expire<'a, K>(&self, k: K, seconds: i64) -> BasicResult<bool>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.expire(k, seconds)).await
+    }
+
+    // The value after adding `delta`, a missing key counts as 0
+    pub async fn incr<'a, K>(&self, k: K, delta: i64) -> BasicResult<i64>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.incr(k, delta)).await
+    }
+
+    pub async fn decr<'a, K>(&self, k: K, delta: i64) -> BasicResult<i64>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.decr(k, delta)).await
+    }
+
+    // In the order of `keys`, `None` for a missing key. In a cluster all keys
+    // must share a slot, see `hash_tag`
+    pub async fn mget<'a, K, V>(&self, keys: &'a [K]) -> BasicResult<Vec<Option<V>>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut conn = self.conn().await?;
+        self.timed(redis::cmd("MGET").arg(keys).query_async(&mut conn))
+            .await
+    }
+
+    pub async fn mset<'a, K, V>(&self, items: &'a [(K, V)]) -> BasicResult<()>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: ToRedisArgs + Send + Sync + 'a,
+    {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.conn().await?;
+        self.timed(conn.mset(items)).await
+    }
+
+    // Stores `v` as is, not as JSON like `hset_struct`, so a string field of a
+    // struct hash reads back with its quotes here and a string written here
+    // fails `hget_struct`. Numbers read the same either way
+    pub async fn hset<'a, K, F, V>(&self, k: K, field: F, v: V) -> BasicResult<()>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        F: ToRedisArgs + Send + Sync + 'a,
+        V: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.hset(k, field, v)).await
+    }
+
+    pub async fn hget<'a, K, F, V>(&self, k: K, field: F) -> BasicResult<Option<V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        F: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.hget(k, field)).await
+    }
+
+    pub async fn hdel<'a, K, F>(&self, k: K, field: F) -> BasicResult<bool>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        F: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.hdel(k, field)).await
+    }
+
+    pub async fn hgetall<'a, K, V>(&self, k: K) -> BasicResult<HashMap<String, V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.hgetall(k)).await
+    }
+
+    pub async fn hincr<'a, K, F>(&self, k: K, field: F, delta: i64) -> BasicResult<i64>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        F: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.hincr(k, field, delta)).await
+    }
+
+    // One hash field per struct field, fields that are `None` are removed.
+    // Every value is JSON, see `hset` before mixing the two on one hash
+    pub async fn hset_struct<T>(&self, k: &str, v: &T) -> BasicResult<()>
+    where
+        T: Serialize,
+    {
+        let (set, del) = encode_fields(v)?;
+        if set.is_empty() && del.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if !set.is_empty() {
+            pipe.cmd("HSET").arg(k).arg(&set).ignore();
+        }
+        if !del.is_empty() {
+            pipe.cmd("HDEL").arg(k).arg(&del).ignore();
+        }
+        let mut conn = self.conn().await?;
+        self.timed(pipe.query_async::<_, ()>(&mut conn)).await
+    }
+
+    // `None` when the hash does not exist
+    pub async fn hget_struct<T>(&self, k: &str) -> BasicResult<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let fields: HashMap<String, String> = self.hgetall(k).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        decode_fields(fields).map(Some)
+    }
+
+    // The list length afterwards
+    pub async fn lpush<'a, K, V>(&self, k: K, v: V) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.lpush(k, v)).await
+    }
+
+    pub async fn rpush<'a, K, V>(&self, k: K, v: V) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.rpush(k, v)).await
+    }
+
+    pub async fn lpop<'a, K, V>(&self, k: K) -> BasicResult<Option<V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.lpop(k, None)).await
+    }
+
+    pub async fn rpop<'a, K, V>(&self, k: K) -> BasicResult<Option<V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.rpop(k, None)).await
+    }
+
+    // Pops up to `count` at once
+    pub async fn lpop_many<'a, K, V>(&self, k: K, count: NonZeroUsize) -> BasicResult<Vec<V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.lpop(k, Some(count))).await
+    }
+
+    // Inclusive, negative indexes count from the end, `0, -1` is the whole list
+    pub async fn lrange<'a, K, V>(&self, k: K, start: isize, stop: isize) -> BasicResult<Vec<V>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        V: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.lrange(k, start, stop)).await
+    }
+
+    pub async fn llen<'a, K>(&self, k: K) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.llen(k)).await
+    }
+
+    // Keeps only `start..=stop`, e.g. `0, 99` for a capped list
+    pub async fn ltrim<'a, K>(&self, k: K, start: isize, stop: isize) -> BasicResult<()>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.ltrim(k, start, stop)).await
+    }
+
+    // How many were not members yet
+    pub async fn sadd<'a, K, M>(&self, k: K, member: M) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.sadd(k, member)).await
+    }
+
+    pub async fn srem<'a, K, M>(&self, k: K, member: M) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.srem(k, member)).await
+    }
+
+    pub async fn smembers<'a, K, M>(&self, k: K) -> BasicResult<Vec<M>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.smembers(k)).await
+    }
+
+    pub async fn sismember<'a, K, M>(&self, k: K, member: M) -> BasicResult<bool>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.sismember(k, member)).await
+    }
+
+    pub async fn scard<'a, K>(&self, k: K) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.scard(k)).await
+    }
+
+    // Adds or moves `member` to `score`, true when it is new
+    pub async fn zadd<'a, K, M>(&self, k: K, member: M, score: f64) -> BasicResult<bool>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zadd(k, member, score)).await
+    }
+
+    // The new score
+    pub async fn zincr<'a, K, M>(&self, k: K, member: M, delta: f64) -> BasicResult<f64>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zincr(k, member, delta)).await
+    }
+
+    pub async fn zrem<'a, K, M>(&self, k: K, member: M) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zrem(k, member)).await
+    }
+
+    pub async fn zscore<'a, K, M>(&self, k: K, member: M) -> BasicResult<Option<f64>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zscore(k, member)).await
+    }
+
+    // 0 for the highest score, `None` when not a member
+    pub async fn zrevrank<'a, K, M>(&self, k: K, member: M) -> BasicResult<Option<usize>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zrevrank(k, member)).await
+    }
+
+    pub async fn zcard<'a, K>(&self, k: K) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zcard(k)).await
+    }
+
+    // Bounds are scores, `"-inf"`, `"+inf"` or exclusive like `"(10"`
+    pub async fn zcount<'a, K, B>(&self, k: K, min: B, max: B) -> BasicResult<usize>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        B: ToRedisArgs + Send + Sync + 'a,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zcount(k, min, max)).await
+    }
+
+    // Members with their scores, highest first, by rank. `0, 9` is a top ten
+    pub async fn ztop<'a, K, M>(
+        &self,
+        k: K,
+        start: isize,
+        stop: isize,
+    ) -> BasicResult<Vec<(M, f64)>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        M: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zrevrange_withscores(k, start, stop)).await
+    }
+
+    // Members with their scores in `min..=max`, lowest first, a page at a time
+    pub async fn zrange_by_score<'a, K, B, M>(
+        &self,
+        k: K,
+        min: B,
+        max: B,
+        offset: isize,
+        count: isize,
+    ) -> BasicResult<Vec<(M, f64)>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        B: ToRedisArgs + Send + Sync + 'a,
+        M: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zrangebyscore_limit_withscores(k, min, max, offset, count))
+            .await
+    }
+
+    // Same as `zrange_by_score`, highest first
+    pub async fn zrevrange_by_score<'a, K, B, M>(
+        &self,
+        k: K,
+        max: B,
+        min: B,
+        offset: isize,
+        count: isize,
+    ) -> BasicResult<Vec<(M, f64)>>
+    where
+        K: ToRedisArgs + Send + Sync + 'a,
+        B: ToRedisArgs + Send + Sync + 'a,
+        M: FromRedisValue,
+    {
+        let mut conn = self.conn().await?;
+        self.timed(conn.zrevrangebyscore_limit_withscores(k, max, min, offset, count))
+            .await
+    }
+
+    fn scan_of(&self, command: &'static str, key: Option<&str>, pattern: Option<&str>) -> Scan {
+        Scan {
+            client: self.clone(),
+            command,
+            key: key.map(String::from),
+            pattern: pattern.map(String::from),
+            count: SCAN_COUNT,
+            node: None,
+        }
+    }
+
+    // Keys matching `pattern`, a page at a time without blocking the server.
+    // Keys changed during the scan may be missed or seen twice. In a cluster
+    // every master is scanned, one after the other
+    pub fn scan(&self, pattern: Option<&str>) -> impl Stream<Item = BasicResult<String>> {
+        stream::once(self.scan_of("SCAN", None, pattern).per_master())
+            .map_ok(|scans| stream::iter(scans).flat_map(Scan::stream))
+            .try_flatten()
+    }
+
+    pub fn hscan<V>(
+        &self,
+        k: &str,
+        pattern: Option<&str>,
+    ) -> impl Stream<Item = BasicResult<(String, V)>>
+    where
+        V: FromRedisValue + Send + 'static,
+    {
+        self.scan_of("HSCAN", Some(k), pattern).stream()
+    }
+
+    pub fn sscan<M>(&self, k: &str, pattern: Option<&str>) -> impl Stream<Item = BasicResult<M>>
+    where
+        M: FromRedisValue + Send + 'static,
+    {
+        self.scan_of("SSCAN", Some(k), pattern).stream()
+    }
+
+    pub fn zscan<M>(
+        &self,
+        k: &str,
+        pattern: Option<&str>,
+    ) -> impl Stream<Item = BasicResult<(M, f64)>>
+    where
+        M: FromRedisValue + Send + 'static,
+    {
+        self.scan_of("ZSCAN", Some(k), pattern).stream()
+    }
+}
+
+pub async fn expire<'a, K>(k: K, seconds: i64) -> BasicResult<bool>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.expire(k, seconds).await
+}
+
+pub async fn incr<'a, K>(k: K, delta: i64) -> BasicResult<i64>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.incr(k, delta).await
+}
+
+pub async fn decr<'a, K>(k: K, delta: i64) -> BasicResult<i64>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.decr(k, delta).await
+}
+
+pub async fn mget<'a, K, V>(keys: &'a [K]) -> BasicResult<Vec<Option<V>>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.mget(keys).await
+}
+
+pub async fn mset<'a, K, V>(items: &'a [(K, V)]) -> BasicResult<()>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.mset(items).await
+}
+
+pub async fn hset<'a, K, F, V>(k: K, field: F, v: V) -> BasicResult<()>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    F: ToRedisArgs + Send + Sync + 'a,
+    V: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.hset(k, field, v).await
+}
+
+pub async fn hget<'a, K, F, V>(k: K, field: F) -> BasicResult<Option<V>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    F: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.hget(k, field).await
+}
+
+pub async fn hdel<'a, K, F>(k: K, field: F) -> BasicResult<bool>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    F: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.hdel(k, field).await
+}
+
+pub async fn hgetall<'a, K, V>(k: K) -> BasicResult<HashMap<String, V>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.hgetall(k).await
+}
+
+pub async fn hincr<'a, K, F>(k: K, field: F, delta: i64) -> BasicResult<i64>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    F: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.hincr(k, field, delta).await
+}
+
+pub async fn hset_struct<T>(k: &str, v: &T) -> BasicResult<()>
+where
+    T: Serialize,
+{
+    default_client()?.hset_struct(k, v).await
+}
+
+pub async fn hget_struct<T>(k: &str) -> BasicResult<Option<T>>
+where
+    T: DeserializeOwned,
+{
+    default_client()?.hget_struct(k).await
+}
+
+pub async fn lpush<'a, K, V>(k: K, v: V) -> BasicResult<usize>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.lpush(k, v).await
+}
+
+pub async fn rpush<'a, K, V>(k: K, v: V) -> BasicResult<usize>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.rpush(k, v).await
+}
+
+pub async fn lpop<'a, K, V>(k: K) -> BasicResult<Option<V>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.lpop(k).await
+}
+
+pub async fn rpop<'a, K, V>(k: K) -> BasicResult<Option<V>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.rpop(k).await
+}
+
+pub async fn lrange<'a, K, V>(k: K, start: isize, stop: isize) -> BasicResult<Vec<V>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    V: FromRedisValue,
+{
+    default_client()?.lrange(k, start, stop).await
+}
+
+pub async fn sadd<'a, K, M>(k: K, member: M) -> BasicResult<usize>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.sadd(k, member).await
+}
+
+pub async fn srem<'a, K, M>(k: K, member: M) -> BasicResult<usize>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.srem(k, member).await
+}
+
+pub async fn smembers<'a, K, M>(k: K) -> BasicResult<Vec<M>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: FromRedisValue,
+{
+    default_client()?.smembers(k).await
+}
+
+pub async fn sismember<'a, K, M>(k: K, member: M) -> BasicResult<bool>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.sismember(k, member).await
+}
+
+pub async fn zadd<'a, K, M>(k: K, member: M, score: f64) -> BasicResult<bool>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.zadd(k, member, score).await
+}
+
+pub async fn zincr<'a, K, M>(k: K, member: M, delta: f64) -> BasicResult<f64>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.zincr(k, member, delta).await
+}
+
+pub async fn zrem<'a, K, M>(k: K, member: M) -> BasicResult<usize>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.zrem(k, member).await
+}
+
+pub async fn zscore<'a, K, M>(k: K, member: M) -> BasicResult<Option<f64>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.zscore(k, member).await
+}
+
+pub async fn zrevrank<'a, K, M>(k: K, member: M) -> BasicResult<Option<usize>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: ToRedisArgs + Send + Sync + 'a,
+{
+    default_client()?.zrevrank(k, member).await
+}
+
+pub async fn ztop<'a, K, M>(k: K, start: isize, stop: isize) -> BasicResult<Vec<(M, f64)>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    M: FromRedisValue,
+{
+    default_client()?.ztop(k, start, stop).await
+}
+
+pub async fn zrange_by_score<'a, K, B, M>(
+    k: K,
+    min: B,
+    max: B,
+    offset: isize,
+    count: isize,
+) -> BasicResult<Vec<(M, f64)>>
+where
+    K: ToRedisArgs + Send + Sync + 'a,
+    B: ToRedisArgs + Send + Sync + 'a,
+    M: FromRedisValue,
+{
+    default_client()?
+        .zrange_by_score(k, min, max, offset, count)
+        .await
+}
+
+pub fn scan(pattern: Option<&str>) -> BasicResult<impl Stream<Item = BasicResult<String>>> {
+    Ok(default_client()?.scan(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+        nickname: Option<String>,
+    }
+
+    #[test]
+    fn test_hash_fields() {
+        let user = User {
+            name: "ann".into(),
+            age: 30,
+            nickname: None,
+        };
+        let (mut set, del) = encode_fields(&user).unwrap();
+        set.sort();
+        assert_eq!(
+            set,
+            vec![
+                ("age".to_string(), "30".to_string()),
+                ("name".to_string(), r#""ann""#.to_string())
+            ]
+        );
+        assert_eq!(del, vec!["nickname".to_string()]);
+        let decoded: User = decode_fields(set.into_iter().collect()).unwrap();
+        assert_eq!(decoded, user);
+        assert!(encode_fields(&1).is_err());
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let reply = Value::Bulk(vec![
+            Value::Data(b"7".to_vec()),
+            Value::Bulk(vec![
+                Value::Data(b"a".to_vec()),
+                Value::Data(b"1.5".to_vec()),
+            ]),
+        ]);
+        let (cursor, items): (u64, Vec<(String, f64)>) =
+            FromRedisValue::from_redis_value(&reply).unwrap();
+        assert_eq!((cursor, items), (7, vec![("a".to_string(), 1.5)]));
+    }
+}
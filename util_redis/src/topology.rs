@@ -0,0 +1,536 @@
+use crate::{open_client, Config, PoolOptions, TlsOptions};
+use redis::aio::{Connection, ConnectionLike, ConnectionManager};
+use redis::cluster::{ClusterClient, ClusterClientBuilder};
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{Sentinel, SentinelNodeConnectionInfo};
+use redis::{
+    Client, Cmd, ConnectionAddr, ConnectionInfo, FromRedisValue, IntoConnectionInfo, Pipeline,
+    RedisConnectionInfo, RedisError, RedisFuture, RedisResult, TlsMode, Value,
+};
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell, RwLock};
+use util_error::{business_error, BasicResult, ErrorKind};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SentinelOptions {
+    pub master_name: String,
+    // e.g. `redis://10.0.0.1:26379`, asked in order until one answers
+    pub nodes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ClusterOptions {
+    // Seed nodes, the rest of the cluster is discovered from them
+    pub nodes: Vec<String>,
+    #[serde(default)]
+    pub read_from_replicas: bool,
+}
+
+// `{tag}:key`, keys sharing a tag hash to the same cluster slot so multi-key
+// commands, transactions and scripts can touch them together
+pub fn hash_tag(tag: &str, key: &str) -> String {
+    format!("{{{}}}:{}", tag, key)
+}
+
+pub fn slot(key: &str) -> u16 {
+    redis::cluster_routing::get_slot(key.as_bytes())
+}
+
+// A shared connection, whichever deployment is behind it
+#[derive(Clone)]
+pub enum Conn {
+    Single(ConnectionManager),
+    // The master found through sentinel, flagged once it stops acting as one
+    Sentinel(ConnectionManager, Arc<AtomicBool>),
+    Cluster(ClusterConnection),
+}
+
+// The master went away or was demoted, ask the sentinels again
+fn is_failover(e: &RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_refusal()
+        || e.is_connection_dropped()
+        || e.code() == Some("READONLY")
+}
+
+impl ConnectionLike for Conn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Conn::Single(c) => c.req_packed_command(cmd),
+            Conn::Sentinel(c, stale) => {
+                let stale = stale.clone();
+                let fut = c.req_packed_command(cmd);
+                Box::pin(async move {
+                    let res = fut.await;
+                    if matches!(&res, Err(e) if is_failover(e)) {
+                        stale.store(true, Ordering::Relaxed);
+                    }
+                    res
+                })
+            }
+            Conn::Cluster(c) => c.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Conn::Single(c) => c.req_packed_commands(cmd, offset, count),
+            Conn::Sentinel(c, stale) => {
+                let stale = stale.clone();
+                let fut = c.req_packed_commands(cmd, offset, count);
+                Box::pin(async move {
+                    let res = fut.await;
+                    if matches!(&res, Err(e) if is_failover(e)) {
+                        stale.store(true, Ordering::Relaxed);
+                    }
+                    res
+                })
+            }
+            Conn::Cluster(c) => c.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Conn::Single(c) | Conn::Sentinel(c, _) => c.get_db(),
+            Conn::Cluster(c) => c.get_db(),
+        }
+    }
+}
+
+pub(crate) async fn connect_timeout<T>(
+    options: &PoolOptions,
+    fut: impl Future<Output = RedisResult<T>>,
+) -> BasicResult<T> {
+    let timeout = Duration::from_millis(options.connect_timeout_ms);
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(v) => Ok(v?),
+        Err(_) => Err(ErrorKind::Timeout),
+    }
+}
+
+async fn open_managers(
+    client: &Client,
+    options: &PoolOptions,
+) -> BasicResult<Vec<ConnectionManager>> {
+    let mut res = Vec::with_capacity(options.size.max(1));
+    for _ in 0..options.size.max(1) {
+        let conn = ConnectionManager::new_with_backoff(
+            client.clone(),
+            2,
+            options.reconnect_backoff_ms,
+            options.reconnect_attempts,
+        );
+        res.push(connect_timeout(options, conn).await?);
+    }
+    Ok(res)
+}
+
+pub(crate) struct Master {
+    client: Client,
+    conns: Vec<ConnectionManager>,
+    stale: Arc<AtomicBool>,
+}
+
+pub(crate) enum Backend {
+    Standalone {
+        client: Client,
+        // Opened on first use, a failed attempt is retried by the next command
+        conns: OnceCell<Vec<ConnectionManager>>,
+    },
+    Sentinel {
+        sentinel: Mutex<Sentinel>,
+        master_name: String,
+        node: SentinelNodeConnectionInfo,
+        master: RwLock<Option<Master>>,
+    },
+    Cluster {
+        client: ClusterClient,
+        nodes: Vec<ConnectionInfo>,
+        tls: Option<TlsOptions>,
+        conn: OnceCell<ClusterConnection>,
+    },
+}
+
+impl Backend {
+    pub(crate) fn new(cfg: Config) -> BasicResult<Self> {
+        if cfg.tls.is_some()
+            && (cfg.sentinel.is_some() || cfg.cluster.is_some())
+            && !cfg!(feature = "tls")
+        {
+            return Err(business_error!(
+                "redis tls not supported, enable the tls feature of util_redis"
+            ));
+        }
+        match (cfg.sentinel.clone(), cfg.cluster.clone()) {
+            (Some(_), Some(_)) => Err(business_error!(
+                "redis sentinel and cluster can not be used together"
+            )),
+            (Some(sentinel), None) => Self::sentinel(&cfg, sentinel),
+            (None, Some(cluster)) => Self::cluster(&cfg, cluster),
+            (None, None) => Ok(Backend::Standalone {
+                client: cfg.client()?,
+                conns: OnceCell::new(),
+            }),
+        }
+    }
+
+    fn sentinel(cfg: &Config, opts: SentinelOptions) -> BasicResult<Self> {
+        if opts.nodes.is_empty() {
+            return Err(business_error!("redis sentinel nodes must not be empty"));
+        }
+        // the master connection only takes a tls mode from sentinel
+        if cfg.tls.as_ref().is_some_and(|v| v.has_certificates()) {
+            return Err(business_error!(
+                "redis sentinel only supports tls with the system roots, unset ca_file, cert_file and key_file"
+            ));
+        }
+        let node = SentinelNodeConnectionInfo {
+            tls_mode: cfg.tls.as_ref().map(|tls| match tls.insecure {
+                true => TlsMode::Insecure,
+                false => TlsMode::Secure,
+            }),
+            redis_connection_info: Some(RedisConnectionInfo {
+                db: cfg.db.unwrap_or_default(),
+                username: cfg.username.clone(),
+                password: cfg.password.as_ref().map(|v| v.expose().clone()),
+            }),
+        };
+        Ok(Backend::Sentinel {
+            sentinel: Mutex::new(Sentinel::build(opts.nodes)?),
+            master_name: opts.master_name,
+            node,
+            master: RwLock::new(None),
+        })
+    }
+
+    fn cluster(cfg: &Config, opts: ClusterOptions) -> BasicResult<Self> {
+        if opts.nodes.is_empty() {
+            return Err(business_error!("redis cluster nodes must not be empty"));
+        }
+        if cfg.db.unwrap_or_default() != 0 {
+            return Err(business_error!("redis cluster only has db 0"));
+        }
+        // the dedicated connections open nodes directly, so they need the
+        // credentials too, not only the cluster client
+        let nodes = opts
+            .nodes
+            .iter()
+            .map(|v| {
+                let mut info = v.as_str().into_connection_info()?;
+                if let Some(tls) = &cfg.tls {
+                    info.addr = tls.addr(info.addr)?;
+                }
+                if cfg.username.is_some() {
+                    info.redis.username = cfg.username.clone();
+                }
+                if let Some(password) = &cfg.password {
+                    info.redis.password = Some(password.expose().clone());
+                }
+                Ok(info)
+            })
+            .collect::<RedisResult<Vec<_>>>()?;
+        let mut builder =
+            ClusterClientBuilder::new(nodes.clone()).retries(cfg.pool.reconnect_attempts as u32);
+        if let Some(username) = &cfg.username {
+            builder = builder.username(username.clone());
+        }
+        if let Some(password) = &cfg.password {
+            builder = builder.password(password.expose().clone());
+        }
+        if opts.read_from_replicas {
+            builder = builder.read_from_replicas();
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &cfg.tls {
+            if tls.has_certificates() {
+                builder = builder.certs(crate::certificates(tls)?);
+            }
+            builder = builder.tls(match tls.insecure {
+                true => TlsMode::Insecure,
+                false => TlsMode::Secure,
+            });
+        }
+        Ok(Backend::Cluster {
+            client: builder.build()?,
+            nodes,
+            tls: cfg.tls.clone(),
+            conn: OnceCell::new(),
+        })
+    }
+
+    pub(crate) async fn conn(&self, options: &PoolOptions, i: usize) -> BasicResult<Conn> {
+        match self {
+            Backend::Standalone { client, conns } => {
+                let conns = conns
+                    .get_or_try_init(|| open_managers(client, options))
+                    .await?;
+                Ok(Conn::Single(conns[i % conns.len()].clone()))
+            }
+            Backend::Sentinel { .. } => {
+                let (conns, stale) = self
+                    .master(options, |m| (m.conns.clone(), m.stale.clone()))
+                    .await?;
+                Ok(Conn::Sentinel(conns[i % conns.len()].clone(), stale))
+            }
+            Backend::Cluster { client, conn, .. } => {
+                let conn = conn
+                    .get_or_try_init(|| connect_timeout(options, client.get_async_connection()))
+                    .await?;
+                Ok(Conn::Cluster(conn.clone()))
+            }
+        }
+    }
+
+    // PUBLISH reaches every node of a cluster, so any node serves SUBSCRIBE
+    pub(crate) async fn dedicated(&self, options: &PoolOptions) -> BasicResult<Connection> {
+        match self {
+            Backend::Standalone { client, .. } => {
+                connect_timeout(options, client.get_async_connection()).await
+            }
+            Backend::Sentinel { .. } => {
+                let client = self.master(options, |m| m.client.clone()).await?;
+                connect_timeout(options, client.get_async_connection()).await
+            }
+            Backend::Cluster { nodes, tls, .. } => {
+                let mut last_err = None;
+                for node in nodes.iter() {
+                    let client = open_client(node.clone(), tls.as_ref())?;
+                    match connect_timeout(options, client.get_async_connection()).await {
+                        Ok(v) => return Ok(v),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| business_error!("redis cluster has no node")))
+            }
+        }
+    }
+
+    // A connection of its own to the node serving `key`, in a cluster the
+    // master of its slot, looked up again on every call so a failover is
+    // followed by reconnecting
+    pub(crate) async fn dedicated_for(
+        &self,
+        options: &PoolOptions,
+        key: &str,
+    ) -> BasicResult<Connection> {
+        if !matches!(self, Backend::Cluster { .. }) {
+            return self.dedicated(options).await;
+        }
+        let slot = slot(key) as i64;
+        let Some((_, _, addr)) = self
+            .slots(options)
+            .await?
+            .into_iter()
+            .find(|(start, end, _)| (*start..=*end).contains(&slot))
+        else {
+            return Err(business_error!(format!(
+                "redis cluster has no master for slot {} of {}",
+                slot, key
+            )));
+        };
+        self.dedicated_to(options, addr).await
+    }
+
+    // A connection of its own to every master of a cluster, `None` for the
+    // other deployments, which only have one
+    pub(crate) async fn dedicated_masters(
+        &self,
+        options: &PoolOptions,
+    ) -> BasicResult<Option<Vec<Connection>>> {
+        if !matches!(self, Backend::Cluster { .. }) {
+            return Ok(None);
+        }
+        let mut addrs = Vec::new();
+        for (_, _, addr) in self.slots(options).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        let mut res = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            res.push(self.dedicated_to(options, addr).await?);
+        }
+        Ok(Some(res))
+    }
+
+    async fn slots(&self, options: &PoolOptions) -> BasicResult<Vec<SlotRange>> {
+        let mut conn = self.dedicated(options).await?;
+        let slots: Vec<Vec<Value>> = redis::cmd("CLUSTER")
+            .arg("SLOTS")
+            .query_async(&mut conn)
+            .await?;
+        Ok(parse_slots(&slots)?)
+    }
+
+    // A cluster node by address, with the credentials and tls of the seed nodes
+    async fn dedicated_to(
+        &self,
+        options: &PoolOptions,
+        (host, port): (String, u16),
+    ) -> BasicResult<Connection> {
+        let Backend::Cluster { nodes, tls, .. } = self else {
+            unreachable!("node address of a backend without cluster")
+        };
+        let mut info = nodes[0].clone();
+        info.addr = match info.addr {
+            ConnectionAddr::TcpTls {
+                insecure,
+                tls_params,
+                ..
+            } => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure,
+                tls_params,
+            },
+            _ => ConnectionAddr::Tcp(host, port),
+        };
+        let client = open_client(info, tls.as_ref())?;
+        connect_timeout(options, client.get_async_connection()).await
+    }
+
+    async fn master<T>(&self, options: &PoolOptions, f: impl Fn(&Master) -> T) -> BasicResult<T> {
+        let Backend::Sentinel {
+            sentinel,
+            master_name,
+            node,
+            master,
+        } = self
+        else {
+            unreachable!("master of a backend without sentinel")
+        };
+        if let Some(m) = master.read().await.as_ref() {
+            if !m.stale.load(Ordering::Relaxed) {
+                return Ok(f(m));
+            }
+        }
+        let mut guard = master.write().await;
+        if let Some(m) = guard.as_ref() {
+            if !m.stale.load(Ordering::Relaxed) {
+                return Ok(f(m));
+            }
+        }
+        let client = connect_timeout(
+            options,
+            sentinel
+                .lock()
+                .await
+                .async_master_for(master_name, Some(node)),
+        )
+        .await?;
+        let conns = open_managers(&client, options).await?;
+        log::info!(
+            "redis sentinel master {} at {}",
+            master_name,
+            client.get_connection_info().addr
+        );
+        let m = Master {
+            client,
+            conns,
+            stale: Default::default(),
+        };
+        let res = f(&m);
+        *guard = Some(m);
+        Ok(res)
+    }
+}
+
+// First and last slot of a range, and the host and port of its master
+type SlotRange = (i64, i64, (String, u16));
+
+fn parse_slots(slots: &[Vec<Value>]) -> RedisResult<Vec<SlotRange>> {
+    let mut res = Vec::with_capacity(slots.len());
+    for range in slots.iter() {
+        let [start, end, master, ..] = range.as_slice() else {
+            continue;
+        };
+        let master: Vec<Value> = FromRedisValue::from_redis_value(master)?;
+        let [host, port, ..] = master.as_slice() else {
+            continue;
+        };
+        res.push((
+            i64::from_redis_value(start)?,
+            i64::from_redis_value(end)?,
+            (
+                String::from_redis_value(host)?,
+                u16::from_redis_value(port)?,
+            ),
+        ));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_tag() {
+        let a = hash_tag("user:1", "profile");
+        let b = hash_tag("user:1", "sessions");
+        assert_eq!(a, "{user:1}:profile");
+        assert_eq!(slot(&a), slot(&b));
+        assert_eq!(slot(&a), slot("user:1"));
+    }
+
+    #[test]
+    fn test_tls_not_dropped() {
+        let cfg = Config {
+            cluster: Some(ClusterOptions {
+                nodes: vec!["redis://127.0.0.1:7000".to_string()],
+                read_from_replicas: false,
+            }),
+            tls: Some(TlsOptions {
+                insecure: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(Backend::new(cfg).is_ok(), cfg!(feature = "tls"));
+
+        let cfg = Config {
+            sentinel: Some(SentinelOptions {
+                master_name: "mymaster".to_string(),
+                nodes: vec!["redis://127.0.0.1:26379".to_string()],
+            }),
+            tls: Some(TlsOptions {
+                ca_file: Some("ca.pem".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(Backend::new(cfg).is_err());
+    }
+
+    #[test]
+    fn test_parse_slots() {
+        let node = |port: i64| {
+            Value::Bulk(vec![
+                Value::Data(b"10.0.0.1".to_vec()),
+                Value::Int(port),
+                Value::Data(b"id".to_vec()),
+            ])
+        };
+        let slots = vec![
+            vec![Value::Int(0), Value::Int(5460), node(7000), node(7003)],
+            vec![Value::Int(5461), Value::Int(10922), node(7001)],
+        ];
+        assert_eq!(
+            parse_slots(&slots).unwrap(),
+            vec![
+                (0, 5460, ("10.0.0.1".to_string(), 7000)),
+                (5461, 10922, ("10.0.0.1".to_string(), 7001)),
+            ]
+        );
+    }
+}
@@ -12,13 +12,9 @@ pub struct MsgResponseWithErrCode {
     pub err_code: usize,
 }
 
-#[derive(Serialize, ToSchema)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
-pub struct Response<D, M>
-where
-    D: Serialize,
-    M: AsRef<str>,
-{
+pub struct Response<D, M> {
     #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<D>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -29,7 +25,7 @@ where
     err_code: Option<usize>,
 }
 
-#[derive(Deserialize, IntoParams)]
+#[derive(Serialize, Deserialize, IntoParams)]
 pub struct Pagination {
     pub index: i64,
     pub size: i64,
@@ -65,6 +61,32 @@ where
     }
 }
 
+impl<D, M> Response<D, M> {
+    pub fn data(&self) -> Option<&D> {
+        self.data.as_ref()
+    }
+
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    pub fn msg(&self) -> Option<&M> {
+        self.msg.as_ref()
+    }
+
+    pub fn err_code(&self) -> Option<usize> {
+        self.err_code
+    }
+
+    pub fn into_data(self) -> Option<D> {
+        self.data
+    }
+
+    pub fn into_parts(self) -> (Option<D>, Option<usize>, Option<M>, Option<usize>) {
+        (self.data, self.total, self.msg, self.err_code)
+    }
+}
+
 pub mod prelude {
     pub use super::{Pagination, Response};
     pub use actix_web::web::{redirect, Json, Redirect};
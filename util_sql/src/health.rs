@@ -0,0 +1,98 @@
+use crate::{Dialect, TracedPool};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec,
+};
+use sqlx::Connection;
+use std::time::{Duration, Instant};
+
+// Every metric is labelled by pool name, metric names are prefixed by
+// `Dialect::PREFIX`, e.g. `postgres_pool_connections`
+pub struct PoolMetrics {
+    connections: IntGaugeVec,
+    idle: IntGaugeVec,
+    pub(crate) acquire_seconds: HistogramVec,
+    pub(crate) acquire_timeouts: IntCounterVec,
+}
+
+impl PoolMetrics {
+    // Registers into the default prometheus registry, once per database
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            connections: register_int_gauge_vec!(
+                format!("{}_pool_connections", prefix),
+                "Open connections, idle or in use",
+                &["pool"]
+            )
+            .unwrap(),
+            idle: register_int_gauge_vec!(
+                format!("{}_pool_idle_connections", prefix),
+                "Idle connections",
+                &["pool"]
+            )
+            .unwrap(),
+            acquire_seconds: register_histogram_vec!(
+                format!("{}_pool_acquire_seconds", prefix),
+                "Time spent waiting for a connection",
+                &["pool"]
+            )
+            .unwrap(),
+            acquire_timeouts: register_int_counter_vec!(
+                format!("{}_pool_acquire_timeouts_total", prefix),
+                "Acquires that hit acquire_timeout_ms",
+                &["pool"]
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl<DB: Dialect> TracedPool<DB> {
+    // Round trip time, including the wait for a connection
+    pub async fn ping(&self) -> sqlx::Result<Duration> {
+        let start = Instant::now();
+        let mut conn = self.acquire().await?;
+        conn.ping().await?;
+        Ok(start.elapsed())
+    }
+
+    // Background ping, logs when the server goes away and comes back. Goes
+    // straight to the pool so it stays out of the acquire metrics
+    pub fn watch(&self, interval: Duration) {
+        let pool = self.inner().clone();
+        tokio::spawn(async move {
+            let mut healthy = true;
+            loop {
+                tokio::time::sleep(interval).await;
+                if pool.is_closed() {
+                    break;
+                }
+                let res = async { pool.acquire().await?.ping().await }.await;
+                match res {
+                    Ok(_) if !healthy => {
+                        log::info!("{} pool recovered", DB::PREFIX);
+                        healthy = true;
+                    }
+                    Err(e) if healthy => {
+                        log::error!("{} pool ping failed, error: {}", DB::PREFIX, e);
+                        healthy = false;
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    // Samples the pool size into its gauges, call before gathering
+    pub fn collect_metrics(&self) {
+        let metrics = DB::metrics();
+        metrics
+            .connections
+            .with_label_values(&[self.name()])
+            .set(self.size() as i64);
+        metrics
+            .idle
+            .with_label_values(&[self.name()])
+            .set(self.num_idle() as i64);
+    }
+}
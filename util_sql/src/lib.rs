@@ -0,0 +1,92 @@
+// Pieces shared by util_postgres, util_mysql and util_sqlite, generic over the
+// sqlx database. Enable the feature of each database in use
+pub mod health;
+pub mod migrate;
+pub mod pool;
+pub mod route;
+pub mod trace;
+pub mod tran;
+
+pub use pool::TracedPool;
+
+use health::PoolMetrics;
+
+// What differs between the databases
+pub trait Dialect: sqlx::Database {
+    // Prefix of metrics and logs
+    const PREFIX: &'static str;
+    // `db.system` on query spans
+    const SYSTEM: &'static str;
+    // Characters delimiting string literals
+    const QUOTES: &'static [char];
+    // First words of a statement `route::is_read_only` accepts
+    const READ_KEYWORDS: &'static [&'static str];
+    // Error codes `with_tran` runs the closure again on
+    const RETRY_CODES: &'static [&'static str];
+
+    fn rows_affected(res: &Self::QueryResult) -> u64;
+
+    fn metrics() -> &'static PoolMetrics;
+}
+
+#[cfg(feature = "postgres")]
+impl Dialect for sqlx::Postgres {
+    const PREFIX: &'static str = "postgres";
+    const SYSTEM: &'static str = "postgresql";
+    // Double quotes delimit identifiers, not strings
+    const QUOTES: &'static [char] = &['\''];
+    const READ_KEYWORDS: &'static [&'static str] = &["select", "show"];
+    // Serialization failures and deadlocks
+    const RETRY_CODES: &'static [&'static str] = &["40001", "40P01"];
+
+    fn rows_affected(res: &sqlx::postgres::PgQueryResult) -> u64 {
+        res.rows_affected()
+    }
+
+    fn metrics() -> &'static PoolMetrics {
+        static METRICS: once_cell::sync::Lazy<PoolMetrics> =
+            once_cell::sync::Lazy::new(|| PoolMetrics::new(sqlx::Postgres::PREFIX));
+        &METRICS
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl Dialect for sqlx::MySql {
+    const PREFIX: &'static str = "mysql";
+    const SYSTEM: &'static str = "mysql";
+    // Double quotes delimit strings too, unless ANSI_QUOTES is set
+    const QUOTES: &'static [char] = &['\'', '"'];
+    const READ_KEYWORDS: &'static [&'static str] = &["select", "show"];
+    // Deadlocks and serialization failures
+    const RETRY_CODES: &'static [&'static str] = &["40001"];
+
+    fn rows_affected(res: &sqlx::mysql::MySqlQueryResult) -> u64 {
+        res.rows_affected()
+    }
+
+    fn metrics() -> &'static PoolMetrics {
+        static METRICS: once_cell::sync::Lazy<PoolMetrics> =
+            once_cell::sync::Lazy::new(|| PoolMetrics::new(sqlx::MySql::PREFIX));
+        &METRICS
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Dialect for sqlx::Sqlite {
+    const PREFIX: &'static str = "sqlite";
+    const SYSTEM: &'static str = "sqlite";
+    const QUOTES: &'static [char] = &['\''];
+    const READ_KEYWORDS: &'static [&'static str] = &["select"];
+    // SQLITE_BUSY and SQLITE_LOCKED, extended codes included
+    const RETRY_CODES: &'static [&'static str] = &["5", "6", "261", "262", "517", "773"];
+
+    fn rows_affected(res: &sqlx::sqlite::SqliteQueryResult) -> u64 {
+        res.rows_affected()
+    }
+
+    fn metrics() -> &'static PoolMetrics {
+        static METRICS: once_cell::sync::Lazy<PoolMetrics> =
+            once_cell::sync::Lazy::new(|| PoolMetrics::new(sqlx::Sqlite::PREFIX));
+        &METRICS
+    }
+}
@@ -0,0 +1,262 @@
+use crate::Dialect;
+use serde::Deserialize;
+use sqlx::migrate::{Migrate, Migration, Migrator};
+use sqlx::Pool;
+use std::collections::HashMap;
+use std::path::Path;
+use util_error::{business_error, validate_error, BasicResult};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MigrateConfig {
+    pub dir: String,
+    pub on_startup: bool,
+    pub locking: bool,
+}
+
+impl Default for MigrateConfig {
+    fn default() -> Self {
+        Self {
+            dir: "migrations".to_string(),
+            on_startup: false,
+            locking: true,
+        }
+    }
+}
+
+impl MigrateConfig {
+    // For migrations embedded with `sqlx::migrate!()` use the macro instead
+    pub async fn migrator(&self) -> BasicResult<Migrator> {
+        let mut res = Migrator::new(Path::new(&self.dir)).await?;
+        res.set_locking(self.locking);
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // applied, but the local file was modified since
+    Drifted,
+    // applied, but the local file is gone
+    Missing,
+}
+
+#[derive(Clone, Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+fn up_migrations(migrator: &Migrator) -> impl Iterator<Item = &Migration> {
+    migrator
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+}
+
+// Takes the lock `run` and `undo` take, so a migration being applied by another
+// process is not reported half done. `up` and `down` release it before the
+// migrator takes it again, the migrator then skips what was applied meanwhile
+pub async fn status<DB>(pool: &Pool<DB>, migrator: &Migrator) -> BasicResult<Vec<MigrationStatus>>
+where
+    DB: Dialect,
+    DB::Connection: Migrate,
+{
+    let mut conn = pool.acquire().await?;
+    if migrator.locking {
+        conn.lock().await?;
+    }
+    let res = read_status(&mut *conn, migrator).await;
+    if migrator.locking {
+        conn.unlock().await?;
+    }
+    res
+}
+
+async fn read_status<C>(conn: &mut C, migrator: &Migrator) -> BasicResult<Vec<MigrationStatus>>
+where
+    C: Migrate + ?Sized,
+{
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(business_error!(format!(
+            "migration {} is partially applied",
+            version
+        )));
+    }
+    let applied = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum))
+        .collect::<HashMap<_, _>>();
+
+    let mut res = up_migrations(migrator)
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            state: match applied.get(&m.version) {
+                Some(checksum) if *checksum == m.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Drifted,
+                None => MigrationState::Pending,
+            },
+        })
+        .collect::<Vec<_>>();
+    if !migrator.ignore_missing {
+        res.extend(
+            applied
+                .keys()
+                .filter(|v| !migrator.version_exists(**v))
+                .map(|v| MigrationStatus {
+                    version: *v,
+                    description: String::new(),
+                    state: MigrationState::Missing,
+                }),
+        );
+    }
+    res.sort_by_key(|m| m.version);
+    Ok(res)
+}
+
+fn check_drift(status: &[MigrationStatus]) -> BasicResult<()> {
+    match status
+        .iter()
+        .find(|m| matches!(m.state, MigrationState::Drifted | MigrationState::Missing))
+    {
+        Some(m) => Err(business_error!(format!(
+            "migration {} {:?}, refuse to migrate",
+            m.version, m.state
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Applies pending migrations, returns them; with `dry_run` only returns them
+pub async fn up<DB>(
+    pool: &Pool<DB>,
+    migrator: &Migrator,
+    dry_run: bool,
+) -> BasicResult<Vec<MigrationStatus>>
+where
+    DB: Dialect,
+    DB::Connection: Migrate,
+{
+    let status = status(pool, migrator).await?;
+    check_drift(&status)?;
+    let pending = status
+        .into_iter()
+        .filter(|m| m.state == MigrationState::Pending)
+        .collect::<Vec<_>>();
+    for m in pending.iter() {
+        log::info!(
+            "{} migrate up{} {} {}",
+            DB::SYSTEM,
+            if dry_run { " (dry run)" } else { "" },
+            m.version,
+            m.description
+        );
+    }
+    if !dry_run && !pending.is_empty() {
+        // the migrator takes the advisory lock itself when `locking` is on
+        migrator.run(pool).await?;
+    }
+    Ok(pending)
+}
+
+// Reverts applied migrations newer than `target`
+pub async fn down<DB>(
+    pool: &Pool<DB>,
+    migrator: &Migrator,
+    target: i64,
+    dry_run: bool,
+) -> BasicResult<Vec<MigrationStatus>>
+where
+    DB: Dialect,
+    DB::Connection: Migrate,
+{
+    let status = status(pool, migrator).await?;
+    check_drift(&status)?;
+    let mut reverting = status
+        .into_iter()
+        .filter(|m| m.state == MigrationState::Applied && m.version > target)
+        .collect::<Vec<_>>();
+    reverting.reverse();
+    for m in reverting.iter() {
+        if !migrator
+            .iter()
+            .any(|v| v.version == m.version && v.migration_type.is_down_migration())
+        {
+            return Err(business_error!(format!(
+                "migration {} is not reversible",
+                m.version
+            )));
+        }
+        log::info!(
+            "{} migrate down{} {} {}",
+            DB::SYSTEM,
+            if dry_run { " (dry run)" } else { "" },
+            m.version,
+            m.description
+        );
+    }
+    if !dry_run && !reverting.is_empty() {
+        migrator.undo(pool, target).await?;
+    }
+    Ok(reverting)
+}
+
+pub async fn run_on_startup<DB>(pool: &Pool<DB>, cfg: &MigrateConfig) -> BasicResult<()>
+where
+    DB: Dialect,
+    DB::Connection: Migrate,
+{
+    if cfg.on_startup {
+        let migrator = cfg.migrator().await?;
+        up(pool, &migrator, false).await?;
+    }
+    Ok(())
+}
+
+// Handles `migrate <up|down <target>|status> [--dry-run]`, returns false for other args
+// so `main` can fall through to starting the service
+pub async fn run_cli<DB, I, S>(pool: &Pool<DB>, migrator: &Migrator, args: I) -> BasicResult<bool>
+where
+    DB: Dialect,
+    DB::Connection: Migrate,
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let args = args
+        .into_iter()
+        .map(|v| v.as_ref().to_string())
+        .collect::<Vec<_>>();
+    let dry_run = args.iter().any(|v| v == "--dry-run");
+    let args = args
+        .iter()
+        .filter(|v| *v != "--dry-run")
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let res = match args.as_slice() {
+        ["migrate", "up"] | ["migrate"] => up(pool, migrator, dry_run).await?,
+        ["migrate", "down", target] => {
+            let target = target
+                .parse::<i64>()
+                .map_err(|e| validate_error!(format!("invalid target version, error: {}", e)))?;
+            down(pool, migrator, target, dry_run).await?
+        }
+        ["migrate", "status"] => status(pool, migrator).await?,
+        ["migrate", ..] => {
+            return Err(validate_error!(
+                "usage: migrate <up|down <target>|status> [--dry-run]"
+            ))
+        }
+        _ => return Ok(false),
+    };
+    for m in res {
+        println!("{:>16} {:<8?} {}", m.version, m.state, m.description);
+    }
+    Ok(true)
+}
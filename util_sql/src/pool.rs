@@ -0,0 +1,196 @@
+use crate::trace::{QuerySpan, TraceOptions};
+use crate::Dialect;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::database::HasStatement;
+use sqlx::pool::PoolConnection;
+use sqlx::{Acquire, Either, Execute, Executor, Pool, Transaction};
+use sqlx_core::describe::Describe;
+use sqlx_core::ext::async_stream::TryAsyncStream;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+use tracing::Instrument;
+
+// A pool whose queries each run inside a `db.query` span, see
+// `trace::traced`. Queries on a connection or transaction taken from it are not
+// traced, wrap those in `traced`
+pub struct TracedPool<DB: Dialect> {
+    pool: Pool<DB>,
+    // The `pool` label of the metrics
+    name: Arc<str>,
+    trace: TraceOptions,
+}
+
+impl<DB: Dialect> Clone for TracedPool<DB> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            name: self.name.clone(),
+            trace: self.trace.clone(),
+        }
+    }
+}
+
+impl<DB: Dialect> fmt::Debug for TracedPool<DB> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracedPool")
+            .field("pool", &self.pool)
+            .field("name", &self.name)
+            .field("trace", &self.trace)
+            .finish()
+    }
+}
+
+impl<DB: Dialect> TracedPool<DB> {
+    pub fn new(name: &str, pool: Pool<DB>, trace: TraceOptions) -> Self {
+        Self {
+            pool,
+            name: name.into(),
+            trace,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+    }
+
+    pub fn inner(&self) -> &Pool<DB> {
+        &self.pool
+    }
+
+    pub fn trace(&self) -> &TraceOptions {
+        &self.trace
+    }
+
+    // Every query, `acquire` and `begin` through here is recorded in the acquire
+    // metrics
+    pub async fn acquire(&self) -> sqlx::Result<PoolConnection<DB>> {
+        let metrics = DB::metrics();
+        let timer = metrics
+            .acquire_seconds
+            .with_label_values(&[&self.name])
+            .start_timer();
+        match self.pool.acquire().await {
+            Ok(v) => {
+                timer.observe_duration();
+                Ok(v)
+            }
+            Err(e) => {
+                timer.stop_and_discard();
+                if matches!(e, sqlx::Error::PoolTimedOut) {
+                    metrics
+                        .acquire_timeouts
+                        .with_label_values(&[&self.name])
+                        .inc();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn begin(&self) -> sqlx::Result<Transaction<'static, DB>> {
+        Transaction::begin(self.acquire().await?).await
+    }
+}
+
+impl<DB: Dialect> Deref for TracedPool<DB> {
+    type Target = Pool<DB>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+impl<'p, DB: Dialect> Executor<'p> for &'_ TracedPool<DB>
+where
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+{
+    type Database = DB;
+
+    fn fetch_many<'e, 'q: 'e, E>(
+        self,
+        query: E,
+    ) -> BoxStream<'e, sqlx::Result<Either<DB::QueryResult, DB::Row>>>
+    where
+        E: Execute<'q, DB> + 'q,
+    {
+        let pool = self.clone();
+        let span = QuerySpan::new::<DB>(&pool.trace, query.sql());
+        let instrument = span.span.clone();
+        TryAsyncStream::new(move |yielder| {
+            async move {
+                let mut rows = 0;
+                let res = async {
+                    let mut conn = pool.acquire().await?;
+                    let mut stream = conn.fetch_many(query);
+                    while let Some(v) = stream.try_next().await? {
+                        rows += match &v {
+                            Either::Left(res) => DB::rows_affected(res),
+                            Either::Right(_) => 1,
+                        };
+                        yielder.r#yield(v).await;
+                    }
+                    Ok(())
+                }
+                .await;
+                span.finish::<DB>(&pool.trace, res.as_ref().map(|_| rows));
+                res
+            }
+            .instrument(instrument)
+        })
+        .boxed()
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E>(self, query: E) -> BoxFuture<'e, sqlx::Result<Option<DB::Row>>>
+    where
+        E: Execute<'q, DB> + 'q,
+    {
+        let pool = self.clone();
+        let span = QuerySpan::new::<DB>(&pool.trace, query.sql());
+        let instrument = span.span.clone();
+        Box::pin(
+            async move {
+                let res = async { pool.acquire().await?.fetch_optional(query).await }.await;
+                span.finish::<DB>(&pool.trace, res.as_ref().map(|v| v.is_some() as u64));
+                res
+            }
+            .instrument(instrument),
+        )
+    }
+
+    fn prepare_with<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+        parameters: &'e [DB::TypeInfo],
+    ) -> BoxFuture<'e, sqlx::Result<<DB as HasStatement<'q>>::Statement>> {
+        let pool = self.clone();
+        Box::pin(async move { pool.acquire().await?.prepare_with(sql, parameters).await })
+    }
+
+    #[doc(hidden)]
+    fn describe<'e, 'q: 'e>(self, sql: &'q str) -> BoxFuture<'e, sqlx::Result<Describe<DB>>> {
+        let pool = self.clone();
+        Box::pin(async move { pool.acquire().await?.describe(sql).await })
+    }
+}
+
+impl<'a, DB: Dialect> Acquire<'a> for &'_ TracedPool<DB> {
+    type Database = DB;
+    type Connection = PoolConnection<DB>;
+
+    fn acquire(self) -> BoxFuture<'static, sqlx::Result<PoolConnection<DB>>> {
+        let pool = self.clone();
+        Box::pin(async move { pool.acquire().await })
+    }
+
+    fn begin(self) -> BoxFuture<'static, sqlx::Result<Transaction<'a, DB>>> {
+        let pool = self.clone();
+        Box::pin(async move { Transaction::begin(pool.acquire().await?).await })
+    }
+}
@@ -0,0 +1,67 @@
+use crate::Dialect;
+
+// What the caller of `route` says a statement does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Whether a statement marked `Access::Read` may go to a replica. Only a plain
+// read qualifies, so a mistaken mark on a locking read or SELECT INTO still
+// reaches the primary
+pub fn is_read_only<DB: Dialect>(sql: &str) -> bool {
+    let words = sql
+        .split_whitespace()
+        .map(|v| v.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let Some(keyword) = words.first() else {
+        return false;
+    };
+    DB::READ_KEYWORDS.contains(&keyword.as_str())
+        && !words.iter().any(|v| v == "into")
+        && !words
+            .windows(2)
+            .any(|w| match (w[0].as_str(), w[1].as_str()) {
+                // FOR UPDATE, FOR SHARE, FOR NO KEY UPDATE, FOR KEY SHARE
+                ("for", "update" | "share" | "no" | "key") => true,
+                // LOCK IN SHARE MODE
+                ("lock", "in") => true,
+                _ => false,
+            })
+}
+
+#[cfg(all(test, feature = "postgres", feature = "mysql", feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use sqlx::{MySql, Postgres, Sqlite};
+
+    #[test]
+    fn test_is_read_only() {
+        assert!(is_read_only::<Postgres>("SELECT * FROM users"));
+        assert!(is_read_only::<Postgres>("  select 1"));
+        assert!(is_read_only::<MySql>("SHOW TABLES"));
+        assert!(!is_read_only::<Sqlite>("SHOW TABLES"));
+        assert!(!is_read_only::<Postgres>("SELECT * FROM users FOR UPDATE"));
+        assert!(!is_read_only::<Postgres>(
+            "SELECT * FROM users\nFOR  UPDATE"
+        ));
+        assert!(!is_read_only::<Postgres>(
+            "SELECT * FROM users FOR NO KEY UPDATE"
+        ));
+        assert!(!is_read_only::<MySql>(
+            "SELECT * FROM users LOCK IN SHARE MODE"
+        ));
+        assert!(!is_read_only::<Postgres>(
+            "SELECT *\tINTO archive FROM users"
+        ));
+        assert!(!is_read_only::<Postgres>(
+            "EXPLAIN ANALYZE DELETE FROM users"
+        ));
+        assert!(!is_read_only::<Postgres>(
+            "INSERT INTO users (name) VALUES ('a')"
+        ));
+        assert!(!is_read_only::<MySql>("update users set name = 'a'"));
+        assert!(!is_read_only::<Postgres>(""));
+    }
+}
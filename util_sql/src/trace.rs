@@ -0,0 +1,273 @@
+use crate::Dialect;
+use serde::Deserialize;
+use sqlx::ConnectOptions;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::{field, Instrument, Span};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(v: LogLevel) -> Self {
+        match v {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TraceOptions {
+    // Every statement, logged by sqlx under the `sqlx::query` target. sqlx logs
+    // the statement as sent, literals included, so only with `log_parameters`
+    pub log_statements: LogLevel,
+    pub slow_threshold_ms: u64,
+    pub slow_level: LogLevel,
+    // Keeps inline literals in `db.statement`, the slow log and sqlx's statement
+    // log. Bound values are never seen by the logger either way
+    pub log_parameters: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self {
+            log_statements: LogLevel::Debug,
+            slow_threshold_ms: 1_000,
+            slow_level: LogLevel::Warn,
+            log_parameters: false,
+        }
+    }
+}
+
+impl TraceOptions {
+    pub fn apply<O>(&self, options: O) -> O
+    where
+        O: ConnectOptions,
+    {
+        let statements = match self.log_parameters {
+            true => self.log_statements.into(),
+            false => log::LevelFilter::Off,
+        };
+        // the slow log is ours, see `traced`
+        options
+            .log_statements(statements)
+            .log_slow_statements(log::LevelFilter::Off, Duration::default())
+    }
+
+    fn slow_threshold(&self) -> Duration {
+        Duration::from_millis(self.slow_threshold_ms)
+    }
+
+    fn statement<DB: Dialect>(&self, sql: &str) -> String {
+        match self.log_parameters {
+            true => sql.to_string(),
+            false => redact::<DB>(sql),
+        }
+    }
+}
+
+// Rows recorded on the query span
+pub trait RowCount {
+    fn row_count(&self) -> u64;
+}
+
+#[cfg(feature = "postgres")]
+impl RowCount for sqlx::postgres::PgQueryResult {
+    fn row_count(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl RowCount for sqlx::postgres::PgRow {
+    fn row_count(&self) -> u64 {
+        1
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl RowCount for sqlx::mysql::MySqlQueryResult {
+    fn row_count(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl RowCount for sqlx::mysql::MySqlRow {
+    fn row_count(&self) -> u64 {
+        1
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RowCount for sqlx::sqlite::SqliteQueryResult {
+    fn row_count(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RowCount for sqlx::sqlite::SqliteRow {
+    fn row_count(&self) -> u64 {
+        1
+    }
+}
+
+// A single scalar, e.g. `COUNT(*)` or a returned id
+impl RowCount for i64 {
+    fn row_count(&self) -> u64 {
+        1
+    }
+}
+
+impl<T> RowCount for Vec<T> {
+    fn row_count(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<T> RowCount for Option<T> {
+    fn row_count(&self) -> u64 {
+        self.is_some() as u64
+    }
+}
+
+// Runs `fut` inside a `db.query` span carrying `db.system`, `db.statement`,
+// `db.rows` and `db.duration_ms`, and logs it when slower than the threshold.
+// `sql` is only used for the span and the log
+pub async fn traced<DB, T, Fut>(opts: &TraceOptions, sql: &str, fut: Fut) -> sqlx::Result<T>
+where
+    DB: Dialect,
+    Fut: Future<Output = sqlx::Result<T>>,
+    T: RowCount,
+{
+    let span = QuerySpan::new::<DB>(opts, sql);
+    let res = fut.instrument(span.span.clone()).await;
+    span.finish::<DB>(opts, res.as_ref().map(|v| v.row_count()));
+    res
+}
+
+pub(crate) struct QuerySpan {
+    pub(crate) span: Span,
+    statement: String,
+    start: Instant,
+}
+
+impl QuerySpan {
+    pub(crate) fn new<DB: Dialect>(opts: &TraceOptions, sql: &str) -> Self {
+        let statement = opts.statement::<DB>(sql);
+        let span = tracing::info_span!(
+            "db.query",
+            db.system = DB::SYSTEM,
+            db.statement = %statement,
+            db.rows = field::Empty,
+            db.duration_ms = field::Empty,
+            error = field::Empty,
+        );
+        Self {
+            span,
+            statement,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish<DB: Dialect>(self, opts: &TraceOptions, res: Result<u64, &sqlx::Error>) {
+        let elapsed = self.start.elapsed();
+        self.span
+            .record("db.duration_ms", elapsed.as_millis() as u64);
+        match res {
+            Ok(rows) => self.span.record("db.rows", rows),
+            Err(e) => self.span.record("error", field::display(e)),
+        };
+        if elapsed >= opts.slow_threshold() {
+            if let Some(level) = log::LevelFilter::from(opts.slow_level).to_level() {
+                log::log!(
+                    level,
+                    "slow {} query, elapsed: {:?}, statement: {}",
+                    DB::SYSTEM,
+                    elapsed,
+                    self.statement
+                );
+            }
+        }
+    }
+}
+
+// Replaces string and numeric literals with `?`, placeholders such as `$1` or
+// `?1` stay
+pub fn redact<DB: Dialect>(sql: &str) -> String {
+    let mut res = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut prev: Option<char> = None;
+    while let Some(c) = chars.next() {
+        let in_ident =
+            prev.is_some_and(|p| p.is_alphanumeric() || p == '_' || p == '$' || p == '?');
+        if DB::QUOTES.contains(&c) {
+            // a doubled quote is an escaped quote inside the literal
+            let quote = c;
+            while let Some(c) = chars.next() {
+                if c == quote {
+                    if chars.peek() == Some(&quote) {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+            }
+            res.push('?');
+        } else if c.is_ascii_digit() && !in_ident {
+            while chars
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '.')
+            {
+                chars.next();
+            }
+            res.push('?');
+        } else {
+            res.push(c);
+        }
+        prev = Some(c);
+    }
+    res
+}
+
+#[cfg(all(test, feature = "postgres", feature = "mysql"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact::<sqlx::Postgres>(
+                "SELECT * FROM t1 WHERE id = 42 AND name = 'o''brien' AND v = $1"
+            ),
+            "SELECT * FROM t1 WHERE id = ? AND name = ? AND v = $1"
+        );
+        assert_eq!(
+            redact::<sqlx::Postgres>("UPDATE t SET x = 1.5e3"),
+            "UPDATE t SET x = ?"
+        );
+        assert_eq!(
+            redact::<sqlx::Postgres>(r#"SELECT "a1" FROM t"#),
+            r#"SELECT "a1" FROM t"#
+        );
+        assert_eq!(
+            redact::<sqlx::MySql>(r#"SELECT * FROM t WHERE a = "x" AND b = ?"#),
+            "SELECT * FROM t WHERE a = ? AND b = ?"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use crate::Dialect;
+pub use futures::future::BoxFuture;
+use sqlx::{Connection, Transaction};
+use std::time::Duration;
+use util_config::RetryPolicy;
+use util_error::{BasicResult, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TranOptions {
+    // Ignored by sqlite, its transactions are always serializable
+    pub isolation: Option<IsolationLevel>,
+    pub read_only: bool,
+    // Retries the whole closure on the errors in `Dialect::RETRY_CODES`
+    pub retry: RetryPolicy,
+    // Postgres only, scopes the transaction to one tenant, see
+    // `util_postgres::tenant::TenantOptions`
+    pub tenant: Option<String>,
+}
+
+impl Default for TranOptions {
+    fn default() -> Self {
+        Self {
+            isolation: None,
+            read_only: false,
+            retry: RetryPolicy {
+                max_attempts: 3,
+                initial_backoff_ms: 10,
+                max_backoff_ms: 1_000,
+                multiplier: 2.0,
+            },
+            tenant: None,
+        }
+    }
+}
+
+impl TranOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn isolation(mut self, isolation: IsolationLevel) -> Self {
+        self.isolation = Some(isolation);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn tenant(mut self, tenant_id: impl Into<String>) -> Self {
+        self.tenant = Some(tenant_id.into());
+        self
+    }
+
+    pub fn set_transaction_sql(&self) -> Option<String> {
+        let mut modes = Vec::new();
+        if let Some(isolation) = self.isolation {
+            modes.push(format!("ISOLATION LEVEL {}", isolation.as_sql()));
+        }
+        if self.read_only {
+            modes.push("READ ONLY".to_string());
+        }
+        match modes.is_empty() {
+            true => None,
+            false => Some(format!("SET TRANSACTION {}", modes.join(", "))),
+        }
+    }
+}
+
+// How each database starts a top level transaction for `TranOptions`
+pub trait TranPool<DB: Dialect>: Sync {
+    fn begin_with<'a>(
+        &'a self,
+        opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<Transaction<'static, DB>>>;
+
+    // Runs right before commit or rollback
+    fn before_end<'a>(
+        &'a self,
+        _conn: &'a mut DB::Connection,
+        _opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// Where `with_tran` runs. On a pool it begins a transaction set up for
+// `TranOptions` and retries it. On a connection already in a transaction, e.g.
+// `&mut **tx` inside an outer `with_tran`, it begins a savepoint and runs once,
+// the options belong to the outer transaction and a serialization failure aborts
+// it anyway. A connection outside a transaction gets a plain one
+pub enum TranOn<'a, P, DB: Dialect> {
+    Pool(&'a P),
+    Conn(&'a mut DB::Connection),
+}
+
+impl<'a, P, DB: Dialect> From<&'a P> for TranOn<'a, P, DB> {
+    fn from(v: &'a P) -> Self {
+        TranOn::Pool(v)
+    }
+}
+
+impl<'a, P, DB: Dialect> From<&'a mut DB::Connection> for TranOn<'a, P, DB> {
+    fn from(v: &'a mut DB::Connection) -> Self {
+        TranOn::Conn(v)
+    }
+}
+
+fn is_retryable<DB: Dialect>(e: &ErrorKind) -> bool {
+    match e {
+        ErrorKind::Sqlx(sqlx::Error::Database(e)) => e
+            .code()
+            .is_some_and(|code| DB::RETRY_CODES.iter().any(|v| *v == code)),
+        _ => false,
+    }
+}
+
+// Commits on `Ok`, rolls back on `Err`, and runs `f` again on `Dialect::RETRY_CODES`
+pub async fn with_tran<'t, DB, P, T, F>(pool: &P, opts: &TranOptions, mut f: F) -> BasicResult<T>
+where
+    DB: Dialect,
+    P: TranPool<DB>,
+    F: for<'c> FnMut(&'c mut Transaction<'t, DB>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        let mut tx: Transaction<'t, DB> = pool.begin_with(opts).await?;
+        let res = f(&mut tx).await;
+        pool.before_end(&mut tx, opts).await?;
+        match finish(tx, res).await {
+            Err(e) if attempt < opts.retry.max_attempts && is_retryable::<DB>(&e) => {
+                let backoff: Duration = opts.retry.backoff(attempt);
+                log::warn!(
+                    "{} tran attempt {} failed, retry in {:?}, error: {}",
+                    DB::PREFIX,
+                    attempt,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+// `with_tran` on a pool, a savepoint or plain transaction on a connection, see
+// `TranOn`
+pub async fn with_tran_on<'a, DB, P, T, F>(
+    on: TranOn<'a, P, DB>,
+    opts: &TranOptions,
+    mut f: F,
+) -> BasicResult<T>
+where
+    DB: Dialect,
+    P: TranPool<DB>,
+    F: for<'c> FnMut(&'c mut Transaction<'a, DB>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    match on {
+        TranOn::Pool(pool) => with_tran(pool, opts, f).await,
+        TranOn::Conn(conn) => {
+            let mut tx = Connection::begin(conn).await?;
+            let res = f(&mut tx).await;
+            finish(tx, res).await
+        }
+    }
+}
+
+async fn finish<DB: Dialect, T>(tx: Transaction<'_, DB>, res: BasicResult<T>) -> BasicResult<T> {
+    match res {
+        Ok(v) => {
+            tx.commit().await?;
+            Ok(v)
+        }
+        Err(e) => {
+            if let Err(rollback_err) = tx.rollback().await {
+                log::error!("{} rollback failed, error: {}", DB::PREFIX, rollback_err);
+            }
+            Err(e)
+        }
+    }
+}
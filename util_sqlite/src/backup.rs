@@ -0,0 +1,30 @@
+use crate::{default_pool, SqlPool};
+use std::path::Path;
+use util_error::{business_error, BasicResult};
+
+impl SqlPool {
+    // Consistent snapshot of the live database through `VACUUM INTO`, readers and
+    // writers keep going meanwhile. Written next to `path` first and renamed, so
+    // `path` never holds a partial copy
+    pub async fn backup(&self, path: impl AsRef<Path>) -> BasicResult<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("backup-tmp");
+        if tmp.exists() {
+            std::fs::remove_file(&tmp)?;
+        }
+        let target = tmp
+            .to_str()
+            .ok_or_else(|| business_error!(format!("invalid backup path {:?}", tmp)))?;
+        sqlx::query("VACUUM INTO ?")
+            .bind(target)
+            .execute(self.pool())
+            .await?;
+        std::fs::rename(&tmp, path)?;
+        log::info!("sqlite backup to {:?} success", path);
+        Ok(())
+    }
+}
+
+pub async fn backup(path: impl AsRef<Path>) -> BasicResult<()> {
+    default_pool().await?.backup(path).await
+}
@@ -1,31 +1,427 @@
-use async_once::AsyncOnce;
-use once_cell::sync::OnceCell;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+pub use sqlx;
+use sqlx::migrate::Migrator;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
 use sqlx::{Pool, Sqlite, Transaction};
-use std::result::Result;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use util_config::{RetryPolicy, Secret, Section};
+use util_error::{business_error, BasicResult};
+pub use util_response::Pagination;
+use util_sql::route::is_read_only;
+pub use util_sql::route::Access;
+pub use util_sql::TracedPool;
 pub type SqlResult<T, E = sqlx::Error> = Result<T, E>;
-static mut POOL: OnceCell<AsyncOnce<Pool<Sqlite>>> = OnceCell::new();
 
-pub async fn tran<'a>() -> SqlResult<Transaction<'a, Sqlite>> {
-    conn().await.begin().await
+pub mod backup;
+pub mod health;
+pub mod migrate;
+pub mod outbox;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod trace;
+pub mod tran;
+pub mod derive {
+    pub use sql_repository_derive::repository;
 }
 
-pub async fn conn() -> &'static Pool<Sqlite> {
-    unsafe {
-        POOL.get_or_init(|| -> AsyncOnce<Pool<Sqlite>> {
-            AsyncOnce::new(async {
-                sqlx::sqlite::SqlitePoolOptions::new()
-                    .test_before_acquire(false)
-                    .connect(&std::env::var("DATABASE_URL").unwrap())
-                    .await
-                    .unwrap()
+pub const PRIMARY: &str = "primary";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub url: Secret<String>,
+    #[serde(default)]
+    pub options: PoolOptions,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub migrate: migrate::MigrateConfig,
+    // Extra pools by name, e.g. `replica` or `analytics`
+    #[serde(default)]
+    pub pools: HashMap<String, PoolConfig>,
+}
+
+impl Section for Config {
+    const NAME: &'static str = "sqlite";
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PoolConfig {
+    pub url: Secret<String>,
+    #[serde(default)]
+    pub options: PoolOptions,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Not used for routing, reachable by name only
+    #[default]
+    Standalone,
+    // Serves `reader()` in round robin
+    Replica,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PoolOptions {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_ms: u64,
+    pub idle_timeout_ms: Option<u64>,
+    pub statement_cache_capacity: usize,
+    // Pings a connection before handing it out, so a restarted server costs one
+    // failed ping instead of one failed query
+    pub test_before_acquire: bool,
+    // Background ping, logs when the server goes away and comes back
+    pub health_check_interval_ms: Option<u64>,
+    pub trace: trace::TraceOptions,
+    // Keeps the mode stored in the database file when unset
+    pub journal_mode: Option<JournalMode>,
+    pub synchronous: Option<Synchronous>,
+    pub busy_timeout_ms: u64,
+    pub foreign_keys: bool,
+    pub create_if_missing: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl From<JournalMode> for SqliteJournalMode {
+    fn from(v: JournalMode) -> Self {
+        match v {
+            JournalMode::Delete => SqliteJournalMode::Delete,
+            JournalMode::Truncate => SqliteJournalMode::Truncate,
+            JournalMode::Persist => SqliteJournalMode::Persist,
+            JournalMode::Memory => SqliteJournalMode::Memory,
+            JournalMode::Wal => SqliteJournalMode::Wal,
+            JournalMode::Off => SqliteJournalMode::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl From<Synchronous> for SqliteSynchronous {
+    fn from(v: Synchronous) -> Self {
+        match v {
+            Synchronous::Off => SqliteSynchronous::Off,
+            Synchronous::Normal => SqliteSynchronous::Normal,
+            Synchronous::Full => SqliteSynchronous::Full,
+            Synchronous::Extra => SqliteSynchronous::Extra,
+        }
+    }
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_ms: 30_000,
+            idle_timeout_ms: Some(600_000),
+            statement_cache_capacity: 100,
+            test_before_acquire: true,
+            health_check_interval_ms: Some(30_000),
+            trace: trace::TraceOptions::default(),
+            journal_mode: None,
+            synchronous: None,
+            busy_timeout_ms: 5_000,
+            foreign_keys: true,
+            create_if_missing: false,
+        }
+    }
+}
+
+impl PoolOptions {
+    fn pool_options(&self) -> SqlitePoolOptions {
+        SqlitePoolOptions::new()
+            .test_before_acquire(self.test_before_acquire)
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(Duration::from_millis(self.acquire_timeout_ms))
+            .idle_timeout(self.idle_timeout_ms.map(Duration::from_millis))
+            // a read only `with_tran` dropped or panicking half way never turns
+            // query_only off itself
+            .after_release(|conn, _| {
+                Box::pin(async move {
+                    sqlx::query("PRAGMA query_only = OFF")
+                        .execute(&mut *conn)
+                        .await?;
+                    Ok(true)
+                })
             })
+    }
+
+    fn connect_options(&self, url: &str) -> BasicResult<SqliteConnectOptions> {
+        let mut res = SqliteConnectOptions::from_str(url)?
+            .statement_cache_capacity(self.statement_cache_capacity)
+            .busy_timeout(Duration::from_millis(self.busy_timeout_ms))
+            .foreign_keys(self.foreign_keys)
+            .create_if_missing(self.create_if_missing);
+        if let Some(v) = self.journal_mode {
+            res = res.journal_mode(v.into());
+        }
+        if let Some(v) = self.synchronous {
+            res = res.synchronous(v.into());
+        }
+        Ok(self.trace.apply(res))
+    }
+}
+
+pub(crate) static DEFAULT_POOL: OnceCell<SqlPool> = OnceCell::const_new();
+static POOLS: Lazy<RwLock<HashMap<String, (&'static SqlPool, Role)>>> = Lazy::new(Default::default);
+static REPLICA_CURSOR: AtomicUsize = AtomicUsize::new(0);
+static READY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone)]
+pub struct SqlPool {
+    pool: TracedPool<Sqlite>,
+}
+
+impl SqlPool {
+    pub async fn connect(
+        url: &str,
+        options: &PoolOptions,
+        retry: &RetryPolicy,
+    ) -> BasicResult<Self> {
+        util_config::retry(retry, "sqlite connect", || Self::connect_with(url, options)).await
+    }
+
+    pub async fn connect_with(url: &str, options: &PoolOptions) -> BasicResult<Self> {
+        let pool = options
+            .pool_options()
+            .connect_with(options.connect_options(url)?)
+            .await?;
+        let res = Self {
+            pool: TracedPool::new(PRIMARY, pool, options.trace.clone()),
+        };
+        if let Some(ms) = options.health_check_interval_ms {
+            res.pool.watch(Duration::from_millis(ms));
+        }
+        Ok(res)
+    }
+
+    pub async fn connect_url(url: &str) -> BasicResult<Self> {
+        Self::connect_with(url, &PoolOptions::default()).await
+    }
+
+    // A fresh in-memory database per call, shared by the connections of the pool
+    // and dropped with it. Meant for tests, each one gets its own migrated database
+    pub async fn memory(migrator: Option<&Migrator>) -> BasicResult<Self> {
+        let options = PoolOptions {
+            // the database is gone once its last connection closes
+            min_connections: 1,
+            idle_timeout_ms: None,
+            health_check_interval_ms: None,
+            ..Default::default()
+        };
+        let res = Self::connect_with("sqlite::memory:", &options).await?;
+        if let Some(migrator) = migrator {
+            migrator.run(res.pool()).await?;
+        }
+        Ok(res)
+    }
+
+    pub fn pool(&self) -> &TracedPool<Sqlite> {
+        &self.pool
+    }
+
+    pub async fn acquire(&self) -> BasicResult<PoolConnection<Sqlite>> {
+        Ok(self.pool.acquire().await?)
+    }
+
+    pub async fn tran<'a>(&self) -> BasicResult<Transaction<'a, Sqlite>> {
+        Ok(Transaction::begin(self.acquire().await?).await?)
+    }
+}
+
+impl From<Pool<Sqlite>> for SqlPool {
+    fn from(pool: Pool<Sqlite>) -> Self {
+        Self {
+            pool: TracedPool::new(PRIMARY, pool, Default::default()),
+        }
+    }
+}
+
+impl Deref for SqlPool {
+    type Target = TracedPool<Sqlite>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.pool
+    }
+}
+
+pub async fn tran<'a>() -> BasicResult<Transaction<'a, Sqlite>> {
+    default_pool().await?.tran().await
+}
+
+pub async fn conn() -> BasicResult<&'static TracedPool<Sqlite>> {
+    Ok(default_pool().await?.pool())
+}
+
+// Falls back to `DATABASE_URL` when neither init function ran
+pub async fn default_pool() -> BasicResult<&'static SqlPool> {
+    #[cfg(feature = "testing")]
+    if let Ok(pool) = testing::SCOPED_POOL.try_with(|v| *v) {
+        return Ok(pool);
+    }
+    DEFAULT_POOL
+        .get_or_try_init(|| async {
+            let url = std::env::var("DATABASE_URL")
+                .map_err(|e| business_error!(format!("DATABASE_URL not found, error: {}", e)))?;
+            SqlPool::connect_url(&url).await
         })
+        .await
+}
+
+// Registered pools live until the process exits
+pub fn register(
+    name: impl Into<String>,
+    mut pool: SqlPool,
+    role: Role,
+) -> BasicResult<&'static SqlPool> {
+    let name = name.into();
+    if name == PRIMARY {
+        return Err(business_error!("the primary pool is registered by init"));
     }
-    .await
+    let mut pools = POOLS.write().unwrap();
+    if pools.contains_key(&name) {
+        return Err(business_error!(format!(
+            "sqlite pool {} already registered",
+            name
+        )));
+    }
+    pool.pool.set_name(&name);
+    let pool: &'static SqlPool = Box::leak(Box::new(pool));
+    pools.insert(name, (pool, role));
+    Ok(pool)
 }
 
-// If something wrong, it will be show at compile time
-pub fn init() {
-    dotenv::dotenv().unwrap();
+pub async fn pool_named(name: &str) -> BasicResult<&'static SqlPool> {
+    if name == PRIMARY {
+        return default_pool().await;
+    }
+    POOLS
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|(pool, _)| *pool)
+        .ok_or_else(|| business_error!(format!("sqlite pool {} not found", name)))
+}
+
+pub async fn conn_named(name: &str) -> BasicResult<&'static TracedPool<Sqlite>> {
+    Ok(pool_named(name).await?.pool())
+}
+
+pub async fn tran_named<'a>(name: &str) -> BasicResult<Transaction<'a, Sqlite>> {
+    pool_named(name).await?.tran().await
+}
+
+// Round robin over replicas, the primary when there is none
+pub async fn reader() -> BasicResult<&'static TracedPool<Sqlite>> {
+    let replica = {
+        let pools = POOLS.read().unwrap();
+        let mut replicas = pools
+            .iter()
+            .filter(|(_, (_, role))| *role == Role::Replica)
+            .map(|(name, (pool, _))| (name, *pool))
+            .collect::<Vec<_>>();
+        replicas.sort_by(|a, b| a.0.cmp(b.0));
+        match replicas.len() {
+            0 => None,
+            n => Some(replicas[REPLICA_CURSOR.fetch_add(1, Ordering::Relaxed) % n].1),
+        }
+    };
+    match replica {
+        Some(pool) => Ok(pool.pool()),
+        None => conn().await,
+    }
+}
+
+pub async fn writer() -> BasicResult<&'static TracedPool<Sqlite>> {
+    conn().await
+}
+
+// `reader()` for statements the caller marks `Access::Read` that are a plain
+// read, `writer()` for everything else. Nothing is sent to a replica on the
+// text alone, a SELECT can still write through a function with side effects
+pub async fn route(sql: &str, access: Access) -> BasicResult<&'static TracedPool<Sqlite>> {
+    if access == Access::Read && is_read_only::<Sqlite>(sql) {
+        reader().await
+    } else {
+        writer().await
+    }
+}
+
+pub fn ready() -> bool {
+    READY.load(Ordering::Relaxed)
+}
+
+async fn init_pools(cfg: Config) -> BasicResult<()> {
+    let mut connected = false;
+    let primary = DEFAULT_POOL
+        .get_or_try_init(|| {
+            connected = true;
+            SqlPool::connect(cfg.url.expose(), &cfg.options, &cfg.retry)
+        })
+        .await?;
+    // e.g. `default_pool()` fell back to `DATABASE_URL` before init ran
+    if !connected {
+        log::warn!("sqlite already initialized, keep the existing primary pool and its options");
+    }
+    migrate::run_on_startup(primary, &cfg.migrate).await?;
+    for (name, pool_cfg) in cfg.pools.iter() {
+        let pool = SqlPool::connect(pool_cfg.url.expose(), &pool_cfg.options, &cfg.retry).await?;
+        register(name, pool, pool_cfg.role)?;
+        log::info!("sqlite pool {} init success", name);
+    }
+
+    READY.store(true, Ordering::Relaxed);
     log::info!("sqlite init success");
-}
\ No newline at end of file
+    Ok(())
+}
+
+pub async fn init() -> BasicResult<()> {
+    if let Err(e) = dotenv::dotenv() {
+        log::warn!("load .env failed, error: {}", e);
+    }
+    let url = std::env::var("DATABASE_URL")
+        .map_err(|e| business_error!(format!("DATABASE_URL not found, error: {}", e)))?;
+    init_pools(Config {
+        url: Secret::new(url),
+        options: PoolOptions::default(),
+        retry: RetryPolicy::default(),
+        migrate: migrate::MigrateConfig::default(),
+        pools: HashMap::new(),
+    })
+    .await
+}
+
+pub async fn init_with_config(cfg: Config) -> BasicResult<()> {
+    init_pools(cfg).await
+}
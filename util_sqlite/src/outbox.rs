@@ -0,0 +1,272 @@
+use crate::{default_pool, SqlPool};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, Transaction};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot::{self, Receiver},
+};
+use util_error::{business_error, BasicResult};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub table: String,
+    pub batch_size: i64,
+    pub poll_interval_ms: u64,
+    // A row failing this many publishes is parked, `failed_at` is set and the
+    // relay moves past it. Clear `failed_at` to send it again
+    pub max_attempts: i32,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            table: "outbox".to_string(),
+            batch_size: 100,
+            poll_interval_ms: 1_000,
+            max_attempts: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct OutboxMessage {
+    pub id: i64,
+    // Fluvio topic or redis channel
+    pub topic: String,
+    #[sqlx(rename = "event_key")]
+    pub key: Option<String>,
+    // JSON
+    pub payload: String,
+    pub attempts: i32,
+}
+
+// Delivery is at least once, a crash between publish and mark sent publishes again
+pub trait Publisher: Send + Sync + 'static {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>>;
+}
+
+impl<F, Fut> Publisher for F
+where
+    F: Fn(OutboxMessage) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = BasicResult<()>> + Send + 'static,
+{
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(self(msg.clone()))
+    }
+}
+
+// Publishes the payload to the redis channel named by the topic, through the default client
+#[cfg(feature = "redis")]
+pub struct RedisPublisher;
+
+#[cfg(feature = "redis")]
+impl Publisher for RedisPublisher {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(util_redis::publish(
+            msg.topic.as_str(),
+            msg.payload.as_str(),
+        ))
+    }
+}
+
+#[cfg(feature = "fluvio")]
+pub struct FluvioPublisher;
+
+#[cfg(feature = "fluvio")]
+impl Publisher for FluvioPublisher {
+    fn publish<'a>(&'a self, msg: &'a OutboxMessage) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(async move {
+            let key = match &msg.key {
+                Some(v) => util_fluvio::RecordKey::from(v.as_str()),
+                None => util_fluvio::RecordKey::NULL,
+            };
+            util_fluvio::produce(msg.topic.as_str(), key, msg.payload.as_str()).await?;
+            Ok(())
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    cfg: OutboxConfig,
+}
+
+impl Outbox {
+    pub fn new(cfg: OutboxConfig) -> Self {
+        Self { cfg }
+    }
+
+    pub async fn create_table(&self, pool: &SqlPool) -> BasicResult<()> {
+        let table = &self.cfg.table;
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                topic TEXT NOT NULL,
+                event_key TEXT,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                sent_at TEXT,
+                failed_at TEXT
+            )",
+            table
+        );
+        sqlx::query(&sql).execute(pool.pool()).await?;
+        let sql = format!(
+            "CREATE INDEX IF NOT EXISTS {0}_pending_idx ON {0} (id) WHERE sent_at IS NULL AND failed_at IS NULL",
+            table
+        );
+        sqlx::query(&sql).execute(pool.pool()).await?;
+        Ok(())
+    }
+
+    // The event is only visible to the relay once `tx` commits
+    pub async fn enqueue<T>(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        topic: &str,
+        key: Option<&str>,
+        payload: &T,
+    ) -> BasicResult<i64>
+    where
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_string(payload)
+            .map_err(|e| business_error!(format!("invalid outbox payload, error: {}", e)))?;
+        let sql = format!(
+            "INSERT INTO {} (topic, event_key, payload) VALUES (?, ?, ?)",
+            self.cfg.table
+        );
+        let res = crate::trace::query(
+            &sql,
+            sqlx::query(&sql)
+                .bind(topic)
+                .bind(key)
+                .bind(payload)
+                .execute(&mut **tx),
+        )
+        .await?;
+        Ok(res.last_insert_rowid())
+    }
+
+    // Publishes one batch in id order and returns how many were sent. Stops at the
+    // first failure and retries the row next round, until it failed `max_attempts`
+    // times and is parked
+    pub async fn relay_once<P>(&self, pool: &SqlPool, publisher: &P) -> BasicResult<usize>
+    where
+        P: Publisher,
+    {
+        let table = &self.cfg.table;
+        // no row locks in sqlite, run a single relay per database. Rows are marked one
+        // by one so the write lock is not held while publishing
+        let sql = format!(
+            "SELECT id, topic, event_key, payload, attempts FROM {} WHERE sent_at IS NULL AND failed_at IS NULL ORDER BY id LIMIT ?",
+            table
+        );
+        let messages: Vec<OutboxMessage> = sqlx::query_as(&sql)
+            .bind(self.cfg.batch_size)
+            .fetch_all(pool.pool())
+            .await?;
+
+        let mut sent = 0;
+        for msg in messages.iter() {
+            match publisher.publish(msg).await {
+                Ok(()) => {
+                    let sql = format!(
+                        "UPDATE {} SET sent_at = CURRENT_TIMESTAMP, attempts = attempts + 1 WHERE id = ?",
+                        table
+                    );
+                    sqlx::query(&sql).bind(msg.id).execute(pool.pool()).await?;
+                    sent += 1;
+                }
+                Err(e) => {
+                    let parked = msg.attempts + 1 >= self.cfg.max_attempts;
+                    let sql = format!(
+                        "UPDATE {} SET attempts = attempts + 1, last_error = ?, failed_at = CASE WHEN ? THEN CURRENT_TIMESTAMP END WHERE id = ?",
+                        table
+                    );
+                    sqlx::query(&sql)
+                        .bind(e.to_string())
+                        .bind(parked)
+                        .bind(msg.id)
+                        .execute(pool.pool())
+                        .await?;
+                    if !parked {
+                        log::warn!("outbox publish {} failed, error: {}", msg.id, e);
+                        break;
+                    }
+                    log::error!(
+                        "outbox publish {} failed {} times, parked, error: {}",
+                        msg.id,
+                        msg.attempts + 1,
+                        e
+                    );
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    // Polls every `poll_interval_ms`. Rows are not claimed, so run a single relay
+    // per database, a second one would publish the same rows again. Send to the
+    // returned sender to stop, the receiver fires once stopped
+    pub fn relay<P>(&self, pool: &SqlPool, publisher: P) -> (Sender<()>, Receiver<()>)
+    where
+        P: Publisher,
+    {
+        let (sender, mut receiver) = mpsc::channel::<()>(1);
+        let (close_sender, close_receiver) = oneshot::channel::<()>();
+        let outbox = self.clone();
+        let pool = pool.clone();
+        let interval = Duration::from_millis(self.cfg.poll_interval_ms);
+
+        tokio::spawn(async move {
+            'l: loop {
+                match outbox.relay_once(&pool, &publisher).await {
+                    // a full batch means there may be more, drain before waiting
+                    Ok(n) if n as i64 == outbox.cfg.batch_size => {
+                        if receiver.try_recv().is_ok() {
+                            break 'l;
+                        }
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!("outbox relay failed, error: {}", e),
+                }
+                tokio::select! {
+                    Some(_) = receiver.recv() => {
+                        break 'l
+                    }
+                    _ = tokio::time::sleep(interval) => {}
+                }
+            }
+            let _ = close_sender.send(());
+        });
+
+        (sender, close_receiver)
+    }
+}
+
+pub async fn enqueue<T>(
+    tx: &mut Transaction<'_, Sqlite>,
+    topic: &str,
+    key: Option<&str>,
+    payload: &T,
+) -> BasicResult<i64>
+where
+    T: Serialize + ?Sized,
+{
+    Outbox::default().enqueue(tx, topic, key, payload).await
+}
+
+pub async fn relay<P>(publisher: P) -> BasicResult<(Sender<()>, Receiver<()>)>
+where
+    P: Publisher,
+{
+    Ok(Outbox::default().relay(default_pool().await?, publisher))
+}
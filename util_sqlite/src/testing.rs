@@ -0,0 +1,224 @@
+use crate::{migrate::MigrateConfig, SqlPool};
+use futures::FutureExt;
+pub use sql_test_derive::sql_test as test;
+use sqlx::query::Query;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Sqlite, Transaction};
+use std::future::Future;
+use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use util_error::{business_error, BasicResult};
+
+tokio::task_local! {
+    // Makes `default_pool()` return the test database inside `run`
+    pub(crate) static SCOPED_POOL: &'static SqlPool;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TestOptions {
+    migrations: Option<PathBuf>,
+    fixtures: Vec<PathBuf>,
+}
+
+impl TestOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Defaults to `migrations` when that directory exists
+    pub fn migrations(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.migrations = Some(dir.into());
+        self
+    }
+
+    // YAML or JSON, tables mapped to rows, loaded in file order
+    pub fn fixture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.fixtures.push(path.into());
+        self
+    }
+}
+
+// A fresh in-memory database, nothing to drop on teardown
+#[derive(Clone)]
+pub struct TestDb {
+    pool: &'static SqlPool,
+}
+
+impl TestDb {
+    pub async fn create(opts: &TestOptions) -> BasicResult<Self> {
+        let pool: &'static SqlPool = Box::leak(Box::new(SqlPool::memory(None).await?));
+        let res = Self { pool };
+        if let Err(e) = res.prepare(opts).await {
+            if let Err(e) = res.teardown().await {
+                log::error!("teardown sqlite test database failed, error: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    async fn prepare(&self, opts: &TestOptions) -> BasicResult<()> {
+        self.migrate(opts).await?;
+        for path in opts.fixtures.iter() {
+            self.load_fixture(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn migrate(&self, opts: &TestOptions) -> BasicResult<()> {
+        let dir = match &opts.migrations {
+            Some(v) => v.clone(),
+            None => PathBuf::from(MigrateConfig::default().dir),
+        };
+        if opts.migrations.is_some() || dir.is_dir() {
+            let migrator = sqlx::migrate::Migrator::new(dir.as_path()).await?;
+            migrator.run(self.pool.pool()).await?;
+        }
+        Ok(())
+    }
+
+    pub fn pool(&self) -> &'static SqlPool {
+        self.pool
+    }
+
+    pub async fn load_fixture(&self, path: impl AsRef<Path>) -> BasicResult<()> {
+        let path = path.as_ref();
+        let tables = read_fixture(path)?;
+        let mut tx = self.pool.tran().await?;
+        for (table, rows) in tables.iter() {
+            for row in rows.iter() {
+                insert(&mut tx, table, row).await?;
+            }
+        }
+        tx.commit().await?;
+        log::debug!("fixture {:?} loaded", path);
+        Ok(())
+    }
+
+    pub async fn teardown(&self) -> BasicResult<()> {
+        self.pool.close().await;
+        Ok(())
+    }
+}
+
+impl Deref for TestDb {
+    type Target = SqlPool;
+
+    fn deref(&self) -> &Self::Target {
+        self.pool
+    }
+}
+
+type Fixture = Vec<(String, Vec<serde_yaml::Mapping>)>;
+
+fn read_fixture(path: &Path) -> BasicResult<Fixture> {
+    let text = std::fs::read_to_string(path)?;
+    // JSON is valid YAML, one parser reads both and keeps the table order
+    let invalid =
+        |e: serde_yaml::Error| business_error!(format!("invalid fixture {:?}, error: {}", path, e));
+    let tables: serde_yaml::Mapping = serde_yaml::from_str(&text).map_err(invalid)?;
+    let mut res = Vec::new();
+    for (table, rows) in tables {
+        let table = table
+            .as_str()
+            .ok_or_else(|| {
+                business_error!(format!(
+                    "invalid fixture {:?}, table name must be a string",
+                    path
+                ))
+            })?
+            .to_string();
+        res.push((table, serde_yaml::from_value(rows).map_err(invalid)?));
+    }
+    Ok(res)
+}
+
+async fn insert(
+    tx: &mut Transaction<'_, Sqlite>,
+    table: &str,
+    row: &serde_yaml::Mapping,
+) -> BasicResult<()> {
+    let mut columns = Vec::new();
+    for k in row.keys() {
+        columns.push(
+            k.as_str()
+                .ok_or_else(|| business_error!(format!("invalid fixture column in {}", table)))?,
+        );
+    }
+    let sql = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table,
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    );
+    let mut query = sqlx::query(&sql);
+    for v in row.values() {
+        query = bind(query, v)?;
+    }
+    query.execute(&mut **tx).await?;
+    Ok(())
+}
+
+fn bind<'q>(
+    query: Query<'q, Sqlite, SqliteArguments<'q>>,
+    v: &serde_yaml::Value,
+) -> BasicResult<Query<'q, Sqlite, SqliteArguments<'q>>> {
+    use serde_yaml::Value;
+    Ok(match v {
+        Value::Null => query.bind(None::<String>),
+        Value::Bool(v) => query.bind(*v),
+        Value::Number(v) => match v.as_i64() {
+            Some(i) => query.bind(i),
+            None => query.bind(v.as_f64()),
+        },
+        Value::String(v) => query.bind(v.clone()),
+        // nested values are stored as JSON text
+        other => query.bind(
+            serde_json::to_string(other)
+                .map_err(|e| business_error!(format!("invalid fixture value, error: {}", e)))?,
+        ),
+    })
+}
+
+// What a test body may return, `()` or any `Result` with a debuggable error
+pub trait TestResult {
+    fn check(self);
+}
+
+impl TestResult for () {
+    fn check(self) {}
+}
+
+impl<E> TestResult for Result<(), E>
+where
+    E: std::fmt::Debug,
+{
+    fn check(self) {
+        self.unwrap()
+    }
+}
+
+// Creates the database, runs `f` with it and tears it down, also when `f` panics.
+// `default_pool()`, `conn()` and `tran()` return the test database inside `f`,
+// but not inside tasks spawned from it
+pub async fn run<F, Fut, T>(opts: TestOptions, f: F)
+where
+    F: FnOnce(TestDb) -> Fut,
+    Fut: Future<Output = T>,
+    T: TestResult,
+{
+    let db = TestDb::create(&opts)
+        .await
+        .expect("create sqlite test database failed");
+    let res = SCOPED_POOL
+        .scope(db.pool(), AssertUnwindSafe(f(db.clone())).catch_unwind())
+        .await;
+    if let Err(e) = db.teardown().await {
+        log::error!("teardown sqlite test database failed, error: {}", e);
+    }
+    match res {
+        Ok(v) => v.check(),
+        Err(e) => std::panic::resume_unwind(e),
+    }
+}
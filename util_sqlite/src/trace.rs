@@ -0,0 +1,85 @@
+use crate::{default_pool, SqlPool, DEFAULT_POOL};
+use once_cell::sync::Lazy;
+use sqlx::Sqlite;
+use std::future::Future;
+use util_error::BasicResult;
+pub use util_sql::trace::{LogLevel, RowCount, TraceOptions};
+
+static DEFAULT_TRACE: Lazy<TraceOptions> = Lazy::new(Default::default);
+
+impl SqlPool {
+    // Runs `fut` inside a `db.query` span carrying `db.system`, `db.statement`,
+    // `db.rows` and `db.duration_ms`, and logs it when slower than the threshold.
+    // `sql` is only used for the span and the log. Queries run on `pool()` or
+    // `conn()` get the span already, this is for transactions and connections
+    pub async fn traced<T, Fut>(&self, sql: &str, fut: Fut) -> BasicResult<T>
+    where
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+        T: RowCount,
+    {
+        Ok(util_sql::trace::traced::<Sqlite, _, _>(self.pool.trace(), sql, fut).await?)
+    }
+}
+
+pub async fn traced<T, Fut>(sql: &str, fut: Fut) -> BasicResult<T>
+where
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+    T: RowCount,
+{
+    default_pool().await?.traced(sql, fut).await
+}
+
+// `traced` for queries on a connection or transaction rather than a pool, with
+// the options of the default pool once initialised
+pub async fn query<T, Fut>(sql: &str, fut: Fut) -> sqlx::Result<T>
+where
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+    T: RowCount,
+{
+    let opts = DEFAULT_POOL
+        .get()
+        .map_or(&*DEFAULT_TRACE, |v| v.pool.trace());
+    util_sql::trace::traced::<Sqlite, _, _>(opts, sql, fut).await
+}
+
+pub fn redact(sql: &str) -> String {
+    util_sql::trace::redact::<Sqlite>(sql)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("SELECT * FROM t1 WHERE id = 42 AND name = 'o''brien' AND v = ?1"),
+            "SELECT * FROM t1 WHERE id = ? AND name = ? AND v = ?1"
+        );
+        assert_eq!(redact("UPDATE t SET x = 1.5e3"), "UPDATE t SET x = ?");
+    }
+
+    #[tokio::test]
+    async fn test_traced() {
+        let pool = SqlPool::memory(None).await.unwrap();
+        let sql = "SELECT 1 UNION ALL SELECT 2";
+        let rows: Vec<i64> = sqlx::query_scalar(sql)
+            .fetch_all(pool.pool())
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![1, 2]);
+        let res = sqlx::query("SELECT * FROM missing")
+            .execute(pool.pool())
+            .await;
+        assert!(res.is_err());
+
+        let mut conn = pool.acquire().await.unwrap();
+        let res = pool
+            .traced(
+                "SELECT",
+                sqlx::query("SELECT * FROM missing").fetch_optional(&mut *conn),
+            )
+            .await;
+        assert!(res.is_err());
+    }
+}
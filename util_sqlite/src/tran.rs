@@ -0,0 +1,243 @@
+use crate::{default_pool, SqlPool};
+use sqlx::{Sqlite, SqliteConnection, Transaction};
+use util_error::{business_error, BasicResult};
+use util_sql::tran::TranPool;
+pub use util_sql::tran::{BoxFuture, IsolationLevel, TranOptions};
+
+pub type TranOn<'a> = util_sql::tran::TranOn<'a, SqlPool, Sqlite>;
+
+impl TranPool<Sqlite> for SqlPool {
+    fn begin_with<'a>(
+        &'a self,
+        opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<Transaction<'static, Sqlite>>> {
+        Box::pin(async move {
+            if opts.tenant.is_some() {
+                return Err(business_error!("tenant scoped transactions need postgres"));
+            }
+            let mut tx = self.tran().await?;
+            if let Some(isolation) = opts.isolation {
+                log::debug!(
+                    "sqlite transactions are always serializable, ignore {}",
+                    isolation.as_sql()
+                );
+            }
+            if opts.read_only {
+                sqlx::query("PRAGMA query_only = ON")
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            Ok(tx)
+        })
+    }
+
+    // query_only is per connection, turn it off before it goes back to the pool.
+    // A transaction dropped before getting here is reset by the pool's
+    // `after_release`
+    fn before_end<'a>(
+        &'a self,
+        conn: &'a mut SqliteConnection,
+        opts: &'a TranOptions,
+    ) -> BoxFuture<'a, BasicResult<()>> {
+        Box::pin(async move {
+            if opts.read_only {
+                sqlx::query("PRAGMA query_only = OFF")
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl SqlPool {
+    // Commits on `Ok`, rolls back on `Err`, and runs `f` again on SQLITE_BUSY and SQLITE_LOCKED
+    pub async fn with_tran<T, F>(&self, opts: &TranOptions, f: F) -> BasicResult<T>
+    where
+        F: for<'c> FnMut(&'c mut Transaction<'static, Sqlite>) -> BoxFuture<'c, BasicResult<T>>,
+    {
+        util_sql::tran::with_tran(self, opts, f).await
+    }
+}
+
+pub async fn with_tran<T, F>(f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, Sqlite>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    with_tran_opts(&TranOptions::default(), f).await
+}
+
+pub async fn with_tran_opts<T, F>(opts: &TranOptions, f: F) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'static, Sqlite>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    default_pool().await?.with_tran(opts, f).await
+}
+
+// Nests: on `&mut **tx` of an outer transaction `f` runs in a SAVEPOINT, on a
+// pool in a new transaction, see `TranOn`
+pub async fn with_tran_on<'a, T, F>(
+    on: impl Into<TranOn<'a>>,
+    opts: &TranOptions,
+    f: F,
+) -> BasicResult<T>
+where
+    F: for<'c> FnMut(&'c mut Transaction<'a, Sqlite>) -> BoxFuture<'c, BasicResult<T>>,
+{
+    util_sql::tran::with_tran_on(on.into(), opts, f).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PoolOptions;
+    use std::time::Duration;
+    use util_error::business_error;
+
+    async fn memory_pool() -> SqlPool {
+        let options = PoolOptions {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let pool = SqlPool::connect_with("sqlite::memory:", &options)
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE t (v INTEGER NOT NULL)")
+            .execute(pool.pool())
+            .await
+            .unwrap();
+        pool
+    }
+
+    async fn count(pool: &SqlPool) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM t")
+            .fetch_one(pool.pool())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_with_tran_commit_and_rollback() {
+        let pool = memory_pool().await;
+        let opts = TranOptions::default();
+
+        pool.with_tran(&opts, |tx| {
+            Box::pin(async move {
+                sqlx::query("INSERT INTO t VALUES (1)")
+                    .execute(&mut **tx)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(count(&pool).await, 1);
+
+        let res: BasicResult<()> = pool
+            .with_tran(&opts, |tx| {
+                Box::pin(async move {
+                    sqlx::query("INSERT INTO t VALUES (2)")
+                        .execute(&mut **tx)
+                        .await?;
+                    Err(business_error!("abort"))
+                })
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(count(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_nested_savepoint() {
+        let pool = memory_pool().await;
+        // a single connection, a second transaction would wait for it forever
+        pool.with_tran(&TranOptions::default(), |tx| {
+            Box::pin(async move {
+                sqlx::query("INSERT INTO t VALUES (1)")
+                    .execute(&mut **tx)
+                    .await?;
+                let res: BasicResult<()> = with_tran_on(&mut **tx, &TranOptions::default(), |sp| {
+                    Box::pin(async move {
+                        sqlx::query("INSERT INTO t VALUES (2)")
+                            .execute(&mut **sp)
+                            .await?;
+                        with_tran_on(&mut **sp, &TranOptions::default(), |inner| {
+                            Box::pin(async move {
+                                sqlx::query("INSERT INTO t VALUES (3)")
+                                    .execute(&mut **inner)
+                                    .await?;
+                                Ok(())
+                            })
+                        })
+                        .await?;
+                        Err(business_error!("abort savepoint"))
+                    })
+                })
+                .await;
+                assert!(res.is_err());
+                Ok(())
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(count(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let pool = memory_pool().await;
+        let res: BasicResult<()> = pool
+            .with_tran(&TranOptions::new().read_only(true), |tx| {
+                Box::pin(async move {
+                    sqlx::query("INSERT INTO t VALUES (1)")
+                        .execute(&mut **tx)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .await;
+        assert!(res.is_err());
+
+        // query_only must not leak back into the pool
+        pool.with_tran(&TranOptions::default(), |tx| {
+            Box::pin(async move {
+                sqlx::query("INSERT INTO t VALUES (1)")
+                    .execute(&mut **tx)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(count(&pool).await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_dropped() {
+        let pool = memory_pool().await;
+        // cancelled half way, `finish` never runs
+        let res = tokio::time::timeout(
+            Duration::from_millis(20),
+            pool.with_tran(&TranOptions::new().read_only(true), |_| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    Ok(())
+                })
+            }),
+        )
+        .await;
+        assert!(res.is_err());
+
+        pool.with_tran(&TranOptions::default(), |tx| {
+            Box::pin(async move {
+                sqlx::query("INSERT INTO t VALUES (1)")
+                    .execute(&mut **tx)
+                    .await?;
+                Ok(())
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(count(&pool).await, 1);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+use util_sqlite::outbox::{Outbox, OutboxConfig, OutboxMessage};
+use util_sqlite::{PoolOptions, SqlPool};
+
+async fn memory_pool() -> SqlPool {
+    let options = PoolOptions {
+        max_connections: 1,
+        ..Default::default()
+    };
+    SqlPool::connect_with("sqlite::memory:", &options)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_outbox() {
+    let pool = memory_pool().await;
+    let outbox = Outbox::default();
+    outbox.create_table(&pool).await.unwrap();
+
+    let mut tx = pool.tran().await.unwrap();
+    outbox
+        .enqueue(&mut tx, "user", Some("1"), &serde_json::json!({"id": 1}))
+        .await
+        .unwrap();
+    tx.rollback().await.unwrap();
+
+    let mut tx = pool.tran().await.unwrap();
+    for id in 2..=3 {
+        outbox
+            .enqueue(&mut tx, "user", None, &serde_json::json!({ "id": id }))
+            .await
+            .unwrap();
+    }
+    tx.commit().await.unwrap();
+
+    // the first publish fails, nothing after it may be sent
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let failing = |_: OutboxMessage| async { Err(anyhow::anyhow!("broker down").into()) };
+    assert_eq!(outbox.relay_once(&pool, &failing).await.unwrap(), 0);
+
+    let sink = sent.clone();
+    let publisher = move |msg: OutboxMessage| {
+        sink.lock().unwrap().push((msg.topic, msg.payload));
+        async { Ok(()) }
+    };
+    assert_eq!(outbox.relay_once(&pool, &publisher).await.unwrap(), 2);
+    assert_eq!(outbox.relay_once(&pool, &publisher).await.unwrap(), 0);
+    assert_eq!(
+        *sent.lock().unwrap(),
+        vec![
+            ("user".to_string(), r#"{"id":2}"#.to_string()),
+            ("user".to_string(), r#"{"id":3}"#.to_string()),
+        ]
+    );
+
+    let attempts: i64 = sqlx::query_scalar("SELECT attempts FROM outbox WHERE id = 1")
+        .fetch_one(pool.pool())
+        .await
+        .unwrap();
+    assert_eq!(attempts, 2);
+}
+
+#[tokio::test]
+async fn test_poison_row_parked() {
+    let pool = memory_pool().await;
+    let outbox = Outbox::new(OutboxConfig {
+        max_attempts: 2,
+        ..Default::default()
+    });
+    outbox.create_table(&pool).await.unwrap();
+    let mut tx = pool.tran().await.unwrap();
+    for topic in ["bad", "good"] {
+        outbox.enqueue(&mut tx, topic, None, "{}").await.unwrap();
+    }
+    tx.commit().await.unwrap();
+
+    let publisher = |msg: OutboxMessage| async move {
+        match msg.topic.as_str() {
+            "bad" => Err(anyhow::anyhow!("rejected").into()),
+            _ => Ok(()),
+        }
+    };
+    // retried in order first, then parked so the row after it goes out
+    assert_eq!(outbox.relay_once(&pool, &publisher).await.unwrap(), 0);
+    assert_eq!(outbox.relay_once(&pool, &publisher).await.unwrap(), 1);
+    assert_eq!(outbox.relay_once(&pool, &publisher).await.unwrap(), 0);
+
+    let parked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM outbox WHERE failed_at IS NOT NULL")
+        .fetch_one(pool.pool())
+        .await
+        .unwrap();
+    assert_eq!(parked, 1);
+}
@@ -0,0 +1,65 @@
+use util_sqlite::{derive::repository, Pagination, PoolOptions, SqlPool};
+
+#[repository(table = "users", pk = "id", soft_delete = "deleted_at", db = "sqlite")]
+#[derive(Debug, PartialEq)]
+struct User {
+    id: i64,
+    #[column(name = "user_name")]
+    name: String,
+    #[column(skip_insert, skip_update)]
+    deleted_at: Option<String>,
+}
+
+async fn memory_pool() -> SqlPool {
+    let options = PoolOptions {
+        max_connections: 1,
+        ..Default::default()
+    };
+    let pool = SqlPool::connect_with("sqlite::memory:", &options)
+        .await
+        .unwrap();
+    sqlx::query(
+        "CREATE TABLE users (id INTEGER PRIMARY KEY, user_name TEXT NOT NULL, deleted_at TEXT)",
+    )
+    .execute(pool.pool())
+    .await
+    .unwrap();
+    pool
+}
+
+fn user(id: i64, name: &str) -> User {
+    User {
+        id,
+        name: name.to_string(),
+        deleted_at: None,
+    }
+}
+
+#[tokio::test]
+async fn test_repository() {
+    let pool = memory_pool().await;
+    for (id, name) in [(1, "a"), (2, "b"), (3, "c")] {
+        user(id, name).insert(pool.pool()).await.unwrap();
+    }
+
+    let mut u = User::get_by_id(pool.pool(), &2).await.unwrap().unwrap();
+    assert_eq!(u, user(2, "b"));
+
+    u.name = "bb".to_string();
+    assert_eq!(u.update(pool.pool()).await.unwrap(), 1);
+    let u = User::get_by_id(pool.pool(), &2).await.unwrap().unwrap();
+    assert_eq!(u.name, "bb");
+
+    assert_eq!(User::soft_delete(pool.pool(), &1).await.unwrap(), 1);
+    assert_eq!(User::get_by_id(pool.pool(), &1).await.unwrap(), None);
+
+    let page = Pagination { index: 1, size: 1 };
+    let (items, total) = User::list(pool.pool(), &page).await.unwrap();
+    assert_eq!(total, 2);
+    assert_eq!(items, vec![user(2, "bb")]);
+
+    let mut tx = pool.tran().await.unwrap();
+    assert_eq!(User::delete(&mut *tx, &3).await.unwrap(), 1);
+    tx.rollback().await.unwrap();
+    assert!(User::get_by_id(pool.pool(), &3).await.unwrap().is_some());
+}
@@ -0,0 +1,76 @@
+use util_sqlite::{JournalMode, PoolOptions, SqlPool, Synchronous};
+
+async fn count(pool: &SqlPool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(pool.pool())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_memory_isolated() {
+    let a = SqlPool::memory(None).await.unwrap();
+    let b = SqlPool::memory(None).await.unwrap();
+    for pool in [&a, &b] {
+        sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY)")
+            .execute(pool.pool())
+            .await
+            .unwrap();
+    }
+    sqlx::query("INSERT INTO users (id) VALUES (1)")
+        .execute(a.pool())
+        .await
+        .unwrap();
+
+    // every connection of `a` sees the row, `b` never does
+    let mut conns = Vec::new();
+    for _ in 0..3 {
+        conns.push(a.acquire().await.unwrap());
+    }
+    drop(conns);
+    assert_eq!(count(&a).await, 1);
+    assert_eq!(count(&b).await, 0);
+}
+
+#[tokio::test]
+async fn test_pragmas_and_backup() {
+    let dir = std::env::temp_dir().join("util_sqlite_test_backup");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let options = PoolOptions {
+        journal_mode: Some(JournalMode::Wal),
+        synchronous: Some(Synchronous::Normal),
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let url = format!("sqlite://{}", dir.join("app.db").display());
+    let pool = SqlPool::connect_with(&url, &options).await.unwrap();
+
+    let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(pool.pool())
+        .await
+        .unwrap();
+    assert_eq!(mode, "wal");
+    let foreign_keys: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(pool.pool())
+        .await
+        .unwrap();
+    assert_eq!(foreign_keys, 1);
+
+    sqlx::query("CREATE TABLE users (id INTEGER PRIMARY KEY)")
+        .execute(pool.pool())
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO users (id) VALUES (1), (2)")
+        .execute(pool.pool())
+        .await
+        .unwrap();
+
+    let target = dir.join("snapshot.db");
+    pool.backup(&target).await.unwrap();
+    let snapshot = SqlPool::connect_url(&format!("sqlite://{}", target.display()))
+        .await
+        .unwrap();
+    assert_eq!(count(&snapshot).await, 2);
+}
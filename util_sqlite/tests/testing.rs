@@ -0,0 +1,35 @@
+use util_sqlite::testing::{self, TestDb};
+
+async fn names(db: &TestDb) -> Vec<String> {
+    sqlx::query_scalar("SELECT user_name FROM users ORDER BY id")
+        .fetch_all(db.pool().pool())
+        .await
+        .unwrap()
+}
+
+#[testing::test(
+    db = "sqlite",
+    migrations = "tests/migrations",
+    fixtures("tests/fixtures/users.yaml", "tests/fixtures/more_users.json")
+)]
+async fn test_fixtures(db: TestDb) {
+    assert_eq!(names(&db).await, vec!["alice", "bob", "carol"]);
+    let profile: String = sqlx::query_scalar("SELECT profile FROM users WHERE id = 1")
+        .fetch_one(db.pool().pool())
+        .await
+        .unwrap();
+    assert_eq!(profile, r#"{"age":30}"#);
+}
+
+// the default pool resolves to the test database, each test gets its own
+#[testing::test(db = "sqlite", migrations = "tests/migrations")]
+async fn test_scoped_default_pool() -> util_error::BasicResult<()> {
+    sqlx::query("INSERT INTO users (id, user_name) VALUES (1, 'dave')")
+        .execute(util_sqlite::conn().await?)
+        .await?;
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(util_sqlite::conn().await?)
+        .await?;
+    assert_eq!(count, 1);
+    Ok(())
+}